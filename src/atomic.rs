@@ -0,0 +1,38 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::Result;
+
+/// Number of the next temporary file of the process.
+static NEXT_TMP: AtomicU64 = AtomicU64::new(0);
+
+/// Creates the new version of a file under a temporary name with `create`,
+/// and moves it over the old one, so the path is never missing or partially
+/// written, e.g. for programs reloading it as soon as it changes.
+///
+/// The temporary name is unique to the process and the call, so concurrent
+/// writers of the same file do not mix their contents: the last one wins.
+pub fn replace(path: &Path, create: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(
+        ".{}.{}.tmp",
+        process::id(),
+        NEXT_TMP.fetch_add(1, Ordering::Relaxed)
+    ));
+    let tmp = PathBuf::from(tmp);
+
+    if let Err(err) = create(&tmp).and_then(|()| Ok(fs::rename(&tmp, path)?)) {
+        let _ = fs::remove_file(&tmp);
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Writes a file with `replace`.
+pub fn write(path: &Path, contents: impl AsRef<[u8]>) -> Result<()> {
+    replace(path, |tmp| Ok(fs::write(tmp, contents)?))
+}
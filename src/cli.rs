@@ -1,18 +1,65 @@
-use clap_derive::Parser;
+use chrono::{Local, NaiveTime, Utc};
+use clap::ArgAction;
+use clap_derive::{Parser, Subcommand};
 
-use crate::configuration::{get_configuration, Settings};
+use crate::{
+    configuration::{load_configuration, Settings},
+    metadata::TagFilter,
+};
 
 pub enum Actions {
-    Launch,
+    Launch(Overrides),
+    Foreground(Overrides),
     Toggle,
     Get,
+    Restore,
     Stop,
+    PauseUntil(i64),
     Resume,
     Set(String),
+    SetTags(TagFilter),
+    ClearTags,
+    ListSessions,
+    CleanSessions,
+    QueueAdd(String),
+    QueueList,
+    QueueClear,
+    QueueRemove(String),
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Manages the wallpapers lined up to be shown next, before the usual selection
+    Queue {
+        #[clap(subcommand)]
+        action: QueueAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum QueueAction {
+    /// Adds a wallpaper at the end of the queue, given by its path, its path relative to the
+    /// wallpaper directory, its name or a part of it
+    Add { wallpaper: String },
+    /// Prints the queued wallpapers, in order
+    List,
+    /// Empties the queue
+    Clear,
+    /// Removes a wallpaper from the queue, by its position in `queue list`, its path, its name or a
+    /// part of it
+    Remove { wallpaper: String },
 }
 
 #[derive(Parser)]
 pub struct Cli {
+    #[clap(subcommand)]
+    command: Option<Commands>,
+
+    /// Runs the wallpaper loop in the foreground instead of as a daemon,
+    /// for service managers like systemd
+    #[clap(long, conflicts_with_all = &["toggle", "get", "set", "resume", "stop", "tags", "exclude_tags", "clear_tags"])]
+    foreground: bool,
+
     /// Toggle wallpaper
     #[clap(short, long, conflicts_with_all = &["get", "seconds", "minutes"])]
     toggle: bool,
@@ -25,11 +72,16 @@ pub struct Cli {
     #[clap(short, long, group = "sleep", conflicts_with = "seconds")]
     minutes: Option<u64>,
 
-    /// Get current wallpaper
-    #[clap(short, long, conflicts_with_all = &["toggle", "seconds", "minutes", "betterlockscreen"])]
+    /// Prints the wallpaper the next change will use
+    #[clap(short, long, visible_alias = "peek", conflicts_with_all = &["toggle", "seconds", "minutes", "betterlockscreen"])]
     get: bool,
 
-    /// Sets the current wallpaper to a specific one.
+    /// Re-applies the stored wallpaper, e.g. at login, without picking a new one
+    #[clap(long, conflicts_with_all = &["toggle", "seconds", "minutes", "get", "set", "resume", "stop", "foreground"])]
+    restore: bool,
+
+    /// Sets the current wallpaper to a specific one, given by its path, its path relative to the
+    /// wallpaper directory, its name or a part of it
     #[clap(long, conflicts_with_all = &["toggle", "seconds", "minutes", "betterlockscreen", "get"])]
     set: Option<String>,
 
@@ -41,9 +93,45 @@ pub struct Cli {
     #[clap(long, conflicts_with_all = &["toggle", "seconds", "minutes", "betterlockscreen", "get", "resume"])]
     stop: bool,
 
-    /// Updates the betterlockscreen wallpaper
+    /// Stops the slideshow for some time, e.g. `90s`, `45m`, `2h` or `1h30m`
+    #[clap(long, value_parser = parse_duration, conflicts_with_all = &["toggle", "seconds", "minutes", "betterlockscreen", "get", "resume", "stop"])]
+    pause_for: Option<u64>,
+
+    /// Stops the slideshow until the given time (`HH:MM`), today or tomorrow
+    #[clap(long, value_parser = parse_time, conflicts_with_all = &["toggle", "seconds", "minutes", "betterlockscreen", "get", "resume", "stop", "pause_for"])]
+    pause_until: Option<i64>,
+
+    /// Only picks wallpapers with one of these tags, until `--clear-tags`
+    #[clap(long, value_delimiter = ',', conflicts_with_all = &["toggle", "get", "set", "resume", "stop"])]
+    tags: Option<Vec<String>>,
+
+    /// Never picks wallpapers with one of these tags, until `--clear-tags`
+    #[clap(long, value_delimiter = ',', conflicts_with_all = &["toggle", "get", "set", "resume", "stop"])]
+    exclude_tags: Option<Vec<String>>,
+
+    /// Goes back to the tag filter of the configuration file
+    #[clap(long, conflicts_with_all = &["toggle", "get", "set", "resume", "stop", "tags", "exclude_tags"])]
+    clear_tags: bool,
+
+    /// Lists the sessions with a stored state and whether their daemon is running
+    #[clap(long, conflicts_with_all = &["toggle", "get", "set", "resume", "stop", "tags", "exclude_tags", "clear_tags", "foreground"])]
+    sessions: bool,
+
+    /// Removes the state of the sessions whose daemon is not running
+    #[clap(long, conflicts_with_all = &["toggle", "get", "set", "resume", "stop", "tags", "exclude_tags", "clear_tags", "foreground", "sessions"])]
+    clean_sessions: bool,
+
+    /// Updates the betterlockscreen wallpaper, `false` disables the lock screen updates
     #[clap(long, group = "input")]
     betterlockscreen: Option<bool>,
+
+    /// Logs more messages (`-v` for debug, `-vv` for trace)
+    #[clap(short, long, action = ArgAction::Count, conflicts_with = "log_level")]
+    verbose: u8,
+
+    /// Minimum level of the logged messages: error, warn, info, debug or trace
+    #[clap(long)]
+    log_level: Option<String>,
 }
 
 impl Cli {
@@ -59,27 +147,49 @@ impl Cli {
 
     #[must_use]
     pub fn get_settings(&self) -> Settings {
-        let mut settings = get_configuration().unwrap_or_else(|_| Settings::default());
+        let settings = load_configuration().unwrap_or_else(|err| {
+            eprintln!("Warning, invalid configuration, using the defaults: {err}");
+            Settings::default()
+        });
+        self.get_overrides().apply(settings)
+    }
 
-        if let Some(seconds) = self.get_seconds() {
-            settings.sleep_time = seconds;
-        }
+    /// Gets the options given on the command line that take precedence over
+    /// the configuration file.
+    #[must_use]
+    pub fn get_overrides(&self) -> Overrides {
+        let log_level = self.log_level.clone().or_else(|| match self.verbose {
+            0 => None,
+            1 => Some("debug".to_owned()),
+            _ => Some("trace".to_owned()),
+        });
 
-        if let Some(betterlockscreen) = self.betterlockscreen {
-            settings.betterlockscreen = betterlockscreen;
+        Overrides {
+            sleep_time: self.get_seconds(),
+            betterlockscreen: self.betterlockscreen,
+            log_level,
         }
-
-        settings
     }
 
     #[must_use]
     pub fn get_action(&self) -> Actions {
+        if let Some(Commands::Queue { action }) = &self.command {
+            return match action {
+                QueueAction::Add { wallpaper } => Actions::QueueAdd(wallpaper.clone()),
+                QueueAction::List => Actions::QueueList,
+                QueueAction::Clear => Actions::QueueClear,
+                QueueAction::Remove { wallpaper } => Actions::QueueRemove(wallpaper.clone()),
+            };
+        }
         if self.toggle {
             return Actions::Toggle;
         }
         if self.get {
             return Actions::Get;
         }
+        if self.restore {
+            return Actions::Restore;
+        }
         if self.resume {
             return Actions::Resume;
         }
@@ -87,10 +197,160 @@ impl Cli {
         if self.stop {
             return Actions::Stop;
         }
+        if let Some(seconds) = self.pause_for {
+            let seconds = i64::try_from(seconds).unwrap_or(i64::MAX);
+            return Actions::PauseUntil(Utc::now().timestamp().saturating_add(seconds));
+        }
+        if let Some(until) = self.pause_until {
+            return Actions::PauseUntil(until);
+        }
         if let Some(wal) = self.set.clone() {
             return Actions::Set(wal);
         }
+        if self.tags.is_some() || self.exclude_tags.is_some() {
+            return Actions::SetTags(TagFilter {
+                include: self.tags.clone().unwrap_or_default(),
+                exclude: self.exclude_tags.clone().unwrap_or_default(),
+            });
+        }
+        if self.clear_tags {
+            return Actions::ClearTags;
+        }
+        if self.foreground {
+            return Actions::Foreground(self.get_overrides());
+        }
+        if self.sessions {
+            return Actions::ListSessions;
+        }
+        if self.clean_sessions {
+            return Actions::CleanSessions;
+        }
+
+        Actions::Launch(self.get_overrides())
+    }
+}
+
+/// The options of the command line that take precedence over the
+/// configuration file. The daemon keeps them to apply them again when the
+/// configuration is reloaded.
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    sleep_time: Option<u64>,
+    betterlockscreen: Option<bool>,
+    log_level: Option<String>,
+}
+
+impl Overrides {
+    /// Applies the options to the settings read from the configuration file.
+    #[must_use]
+    pub fn apply(&self, mut settings: Settings) -> Settings {
+        if let Some(sleep_time) = self.sleep_time {
+            settings.sleep_time = sleep_time;
+        }
+
+        if let Some(betterlockscreen) = self.betterlockscreen {
+            settings.betterlockscreen = betterlockscreen;
+            if !betterlockscreen {
+                settings.lockscreen = None;
+            }
+        }
+
+        if let Some(log_level) = &self.log_level {
+            settings.log_level.clone_from(log_level);
+        }
+
+        settings
+    }
+}
+
+/// Parses a duration such as `90`, `90s`, `45m`, `2h`, `1d` or `1h30m`
+/// into seconds. Numbers without a unit are seconds.
+fn parse_duration(value: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid duration `{value}`, expected e.g. `45m`, `2h` or `1h30m`");
+
+    let mut seconds: u64 = 0;
+    let mut number = String::new();
+    for c in value.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        let n = number.parse::<u64>().map_err(|_| invalid())?;
+        seconds = seconds.saturating_add(n.saturating_mul(unit));
+        number.clear();
+    }
+    if !number.is_empty() {
+        seconds = seconds.saturating_add(number.parse::<u64>().map_err(|_| invalid())?);
+    }
 
-        Actions::Launch
+    if seconds == 0 {
+        return Err(invalid());
+    }
+    Ok(seconds)
+}
+
+/// Parses a time of the day (`HH:MM`) into the unix timestamp of its next
+/// occurrence.
+fn parse_time(value: &str) -> Result<i64, String> {
+    let time = NaiveTime::parse_from_str(value.trim(), "%H:%M")
+        .map_err(|_| format!("invalid time `{value}`, expected HH:MM"))?;
+
+    let now = Local::now();
+    let mut date = now.date_naive();
+    if time <= now.time() {
+        date = date.succ_opt().ok_or("invalid date")?;
+    }
+
+    date.and_time(time)
+        .and_local_timezone(Local)
+        .earliest()
+        .map(|datetime| datetime.timestamp())
+        .ok_or_else(|| format!("`{value}` does not exist in the local timezone"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duration_units() {
+        assert_eq!(parse_duration("45s"), Ok(45));
+        assert_eq!(parse_duration("45m"), Ok(45 * 60));
+        assert_eq!(parse_duration("2h"), Ok(2 * 60 * 60));
+        assert_eq!(parse_duration("1d"), Ok(24 * 60 * 60));
+        assert_eq!(parse_duration("1h30m"), Ok(90 * 60));
+        assert_eq!(parse_duration(" 90 "), Ok(90));
+        assert_eq!(parse_duration("1m30"), Ok(90));
+    }
+
+    #[test]
+    fn invalid_duration() {
+        for value in ["", "0", "0m", "m", "1x", "1.5h", "-1m", "1 h"] {
+            assert!(parse_duration(value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn next_time() {
+        let now = Local::now().timestamp();
+        for value in ["00:00", "12:30", "23:59"] {
+            let time = parse_time(value).unwrap();
+            // Up to a day ahead, plus an hour for the DST changes.
+            assert!(time > now && time <= now + 25 * 60 * 60, "{value}");
+        }
+    }
+
+    #[test]
+    fn invalid_time() {
+        for value in ["", "24:00", "12:60", "noon", "12"] {
+            assert!(parse_time(value).is_err(), "{value}");
+        }
     }
 }
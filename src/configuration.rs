@@ -1,10 +1,87 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
 use serde_derive::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize, Serialize)]
+use crate::{
+    lockscreen::Lockscreen,
+    schedule::{parse_cron, OnWake},
+    setter::{Fit, Setter},
+    sync::SyncTarget,
+};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Settings {
     pub wallpaper_dir: String,
+    /// Legacy option, same as a `lockscreen` section using betterlockscreen.
+    #[serde(default)]
     pub betterlockscreen: bool,
+    /// Lock screen updated with the wallpaper.
+    #[serde(default)]
+    pub lockscreen: Option<Lockscreen>,
     pub sleep_time: u64,
+    /// Align the changes on multiples of `sleep_time` since midnight.
+    #[serde(default)]
+    pub align: bool,
+    /// Cron expression giving the times of the changes, instead of `sleep_time`.
+    #[serde(default)]
+    pub cron: Option<String>,
+    /// What to do with the schedule after the system is suspended.
+    #[serde(default)]
+    pub on_wake: OnWake,
+    /// Scale the wallpapers to the screen resolution before setting them.
+    #[serde(default)]
+    pub prescale: bool,
+    /// Resolution used by `prescale`, detected with `xrandr` if not set.
+    #[serde(default)]
+    pub resolution: Option<String>,
+    /// How the wallpapers are placed on the screen.
+    #[serde(default)]
+    pub fit: Fit,
+    /// Programs used to set the wallpaper, each one is tried in turn until
+    /// one succeeds.
+    #[serde(default = "default_setters")]
+    pub setters: Vec<Setter>,
+    /// Time in seconds after which an external command (setter,
+    /// betterlockscreen...) is considered stuck and killed.
+    #[serde(default = "default_command_timeout")]
+    pub command_timeout: u64,
+    /// Fit modes for specific wallpapers or animated wallpapers (collections),
+    /// by path relative to `wallpaper_dir`.
+    #[serde(default)]
+    pub fit_overrides: HashMap<String, Fit>,
+    /// Default tag filter, used when none has been set with `--tags`.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub exclude_tags: Vec<String>,
+    /// Minimum level of the logged messages (`error`, `warn`, `info`, `debug`
+    /// or `trace`).
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+    /// Size in bytes after which the log file is rotated.
+    #[serde(default = "default_log_max_size")]
+    pub log_max_size: u64,
+    /// Paths kept up to date with the current wallpaper.
+    #[serde(default)]
+    pub sync_targets: Vec<SyncTarget>,
+    #[serde(default = "default_templates_dir")]
+    pub templates_dir: String,
+    #[serde(default)]
+    pub templates: Vec<Template>,
+}
+
+/// A template rendered with the colors of the wallpaper after every change.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Template {
+    /// Name of the template file inside `templates_dir`.
+    pub template: String,
+    /// Path where the rendered template is written.
+    pub output: String,
+    /// Optional shell command run after the template has been written.
+    pub reload: Option<String>,
 }
 
 impl Default for Settings {
@@ -15,19 +92,124 @@ impl Default for Settings {
                 dirs::home_dir().unwrap().to_str().unwrap()
             ),
             betterlockscreen: false,
+            lockscreen: None,
             sleep_time: 1800,
+            align: false,
+            cron: None,
+            on_wake: OnWake::default(),
+            prescale: false,
+            resolution: None,
+            fit: Fit::default(),
+            setters: default_setters(),
+            command_timeout: default_command_timeout(),
+            fit_overrides: HashMap::new(),
+            tags: Vec::new(),
+            exclude_tags: Vec::new(),
+            log_level: default_log_level(),
+            log_max_size: default_log_max_size(),
+            sync_targets: Vec::new(),
+            templates_dir: default_templates_dir(),
+            templates: Vec::new(),
         }
     }
 }
 
-pub fn get_configuration() -> Result<Settings, config::ConfigError> {
-    let config_path = format!(
+impl Settings {
+    /// Gets the lock screen to update: the `lockscreen` section or, if there
+    /// is none and `betterlockscreen` is enabled, betterlockscreen.
+    #[must_use]
+    pub fn get_lockscreen(&self) -> Option<Lockscreen> {
+        self.lockscreen
+            .clone()
+            .or_else(|| self.betterlockscreen.then(Lockscreen::default))
+    }
+
+    /// Gets the fit mode of a wallpaper: its own override, the override of
+    /// the collection containing it or the global one.
+    #[must_use]
+    pub fn get_fit(&self, wallpaper: &str) -> Fit {
+        let Ok(relative) = Path::new(wallpaper).strip_prefix(&self.wallpaper_dir) else {
+            return self.fit;
+        };
+
+        relative
+            .ancestors()
+            .filter_map(|path| path.to_str())
+            .find_map(|path| self.fit_overrides.get(path).copied())
+            .unwrap_or(self.fit)
+    }
+}
+
+fn default_setters() -> Vec<Setter> {
+    vec![Setter::default()]
+}
+
+const fn default_command_timeout() -> u64 {
+    30
+}
+
+fn default_log_level() -> String {
+    "info".to_owned()
+}
+
+const fn default_log_max_size() -> u64 {
+    1024 * 1024
+}
+
+fn default_templates_dir() -> String {
+    format!(
+        "{}/wallshift/templates",
+        dirs::config_dir().unwrap().to_str().unwrap()
+    )
+}
+
+/// Expands a leading `~` to the home directory of the user.
+#[must_use]
+pub fn expand_home(path: &str) -> String {
+    match (path.strip_prefix('~'), dirs::home_dir()) {
+        (Some(rest), Some(home)) if rest.is_empty() || rest.starts_with('/') => {
+            format!("{}{rest}", home.to_str().unwrap_or_default())
+        }
+        _ => path.to_owned(),
+    }
+}
+
+/// Returns the path of the configuration file.
+#[must_use]
+pub fn get_config_path() -> String {
+    format!(
         "{}/wallshift/config.yml",
         dirs::config_dir().unwrap().to_str().unwrap()
-    );
+    )
+}
+
+/// Reads the configuration file, or gives the default settings if there is
+/// none: running without a configuration file is fine.
+pub fn load_configuration() -> Result<Settings, config::ConfigError> {
+    if Path::new(&get_config_path()).exists() {
+        get_configuration()
+    } else {
+        Ok(Settings::default())
+    }
+}
+
+pub fn get_configuration() -> Result<Settings, config::ConfigError> {
+    let config_path = get_config_path();
     let settings = config::Config::builder()
         .add_source(config::File::new(&config_path, config::FileFormat::Yaml))
         .build()?;
 
-    settings.try_deserialize::<Settings>()
+    let mut settings = settings.try_deserialize::<Settings>()?;
+    // Expanded once here, the wallpaper directory is compared with the paths
+    // of the wallpapers in many places.
+    settings.wallpaper_dir = Path::new(&expand_home(&settings.wallpaper_dir))
+        .components()
+        .collect::<PathBuf>()
+        .to_string_lossy()
+        .into_owned();
+    // Checked once here, rather than every time the next change is planned.
+    if let Some(expression) = &settings.cron {
+        parse_cron(expression).map_err(|err| config::ConfigError::Message(err.to_string()))?;
+    }
+    Ok(settings)
 }
@@ -0,0 +1,141 @@
+use std::{
+    io::{BufRead, BufReader, ErrorKind, Write},
+    net::Shutdown,
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::runtime::get_runtime_path;
+
+/// Maximum time a client waits for the daemon to answer.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A message sent to the daemon through its control socket.
+///
+/// They are sent as a single line of text, `<command> [argument]`, and the
+/// daemon answers with `ok` or `error <message>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Changes the wallpaper now.
+    Next,
+    /// Sets a specific wallpaper.
+    Set(String),
+    /// Pauses the slideshow, until the given unix timestamp or indefinitely.
+    Pause(Option<i64>),
+    Resume,
+    /// Reloads the configuration.
+    Reload,
+}
+
+impl Command {
+    fn to_line(&self) -> String {
+        match self {
+            Self::Next => "next".to_owned(),
+            Self::Set(path) => format!("set {path}"),
+            Self::Pause(None) => "pause".to_owned(),
+            Self::Pause(Some(until)) => format!("pause {until}"),
+            Self::Resume => "resume".to_owned(),
+            Self::Reload => "reload".to_owned(),
+        }
+    }
+
+    /// Parses a command received by the daemon.
+    pub fn parse(line: &str) -> Result<Self> {
+        let (command, argument) = match line.trim_end().split_once(' ') {
+            Some((command, argument)) => (command, Some(argument)),
+            None => (line.trim_end(), None),
+        };
+
+        match (command, argument) {
+            ("next", None) => Ok(Self::Next),
+            ("set", Some(path)) => Ok(Self::Set(path.to_owned())),
+            ("pause", None) => Ok(Self::Pause(None)),
+            ("pause", Some(until)) => Ok(Self::Pause(Some(until.parse()?))),
+            ("resume", None) => Ok(Self::Resume),
+            ("reload", None) => Ok(Self::Reload),
+            _ => Err(anyhow!("invalid command `{}`", line.trim_end())),
+        }
+    }
+}
+
+/// Returns the path of the control socket of the daemon of the current session.
+pub fn get_socket_path() -> Result<PathBuf> {
+    get_runtime_path("sock")
+}
+
+/// Sends a command to the daemon of the current session and waits for it to
+/// be handled.
+///
+/// Returns `false` if there is no daemon listening, in which case the caller
+/// has to do the work itself.
+pub fn send_command(command: &Command) -> Result<bool> {
+    let mut stream = match UnixStream::connect(get_socket_path()?) {
+        Ok(stream) => stream,
+        Err(err)
+            if matches!(
+                err.kind(),
+                ErrorKind::NotFound | ErrorKind::ConnectionRefused
+            ) =>
+        {
+            return Ok(false)
+        }
+        Err(err) => return Err(err.into()),
+    };
+    stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+
+    writeln!(stream, "{}", command.to_line())?;
+    stream.shutdown(Shutdown::Write)?;
+
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    match reply.trim_end() {
+        "ok" => Ok(true),
+        reply => Err(anyhow!(
+            "{}",
+            reply
+                .strip_prefix("error ")
+                .unwrap_or("the daemon did not answer")
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let commands = [
+            Command::Next,
+            Command::Set("/home/user/Wallpapers/beach.png".to_owned()),
+            Command::Set("/home/user/My Wallpapers/a  b.png".to_owned()),
+            Command::Pause(None),
+            Command::Pause(Some(1_700_000_000)),
+            Command::Resume,
+            Command::Reload,
+        ];
+        for command in commands {
+            let line = command.to_line();
+            assert_eq!(Command::parse(&format!("{line}\n")).unwrap(), command);
+        }
+    }
+
+    #[test]
+    fn invalid_commands() {
+        for line in [
+            "",
+            "unknown",
+            "set",
+            "next now",
+            "pause tomorrow",
+            "resume 12",
+            "reload all",
+            "NEXT",
+        ] {
+            assert!(Command::parse(line).is_err(), "{line}");
+        }
+    }
+}
@@ -0,0 +1,564 @@
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use daemonize::Daemonize;
+use log::{debug, error, info, warn};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1, SIGUSR2},
+    iterator::Signals,
+};
+
+use crate::{
+    cli::Overrides,
+    configuration::{load_configuration, Settings},
+    control::{get_socket_path, Command},
+    data::{
+        discard_wallpaper, get_current_wallpaper, get_next_change, get_pause, pause, resume,
+        set_next_change, Pause,
+    },
+    path::{File, ImagePath},
+    pipeline::forget_resolution,
+    runtime::{get_runtime_path, open_runtime_file},
+    schedule::{compute_next_change, Clock, SystemClock, Timer},
+    systemd::notify,
+    wallpaper::{get_first_image, peek_next_wallpaper, restore_wallpaper, update_wallpaper},
+};
+
+/// Something the daemon has to react to.
+enum Event {
+    Signal(i32),
+    /// A command received on the control socket, with the channel on which
+    /// the result is sent back.
+    Control(Command, Sender<Result<()>>),
+    /// The configuration file has been modified.
+    ConfigChanged,
+    /// Files have been added to or removed from the wallpaper directory.
+    LibraryChanged,
+}
+
+/// Starts the daemon in the background. Its pid file and output are stored
+/// in the runtime directory of the user, there is one daemon per session.
+pub fn run_daemon(settings: Settings, overrides: Overrides) -> Result<()> {
+    check_not_running()?;
+
+    let pid_file = get_runtime_path("pid")?;
+    // Appended to, so the output of the previous run is kept.
+    let stdout = open_runtime_file(&get_runtime_path("out")?)?;
+    let stderr = open_runtime_file(&get_runtime_path("err")?)?;
+
+    let daemonize = Daemonize::new()
+        .pid_file(&pid_file)
+        .chown_pid_file(true)
+        .stdout(stdout) // Redirect stdout
+        .stderr(stderr); // Redirect stderr
+
+    daemonize.start()?;
+    // The threads listening for events must be started after the fork.
+    Daemon::start(settings, overrides, Box::new(SystemClock))?.run();
+
+    fs::remove_file(&pid_file)?;
+    info!("stopped");
+    Ok(())
+}
+
+/// Runs the daemon in the current process, for service managers like
+/// systemd. The service manager is notified once the daemon listens for
+/// commands and every time the wallpaper changes.
+pub fn run_foreground(settings: Settings, overrides: Overrides) -> Result<()> {
+    check_not_running()?;
+    let daemon = Daemon::start(settings, overrides, Box::new(SystemClock))?;
+
+    if let Err(err) = notify("READY=1") {
+        error!("failed to notify the service manager: {err}");
+    }
+    daemon.run();
+    if let Err(err) = notify("STOPPING=1") {
+        error!("failed to notify the service manager: {err}");
+    }
+    info!("stopped");
+    Ok(())
+}
+
+/// Fails if a daemon already answers on the control socket of the session.
+fn check_not_running() -> Result<()> {
+    if UnixStream::connect(get_socket_path()?).is_ok() {
+        return Err(anyhow!("the daemon is already running in this session"));
+    }
+    Ok(())
+}
+
+/// State of the daemon. Every event goes through it, and it decides when
+/// the wallpaper changes and to which one.
+struct Daemon {
+    settings: Settings,
+    /// Options of the command line, applied again on every reload.
+    overrides: Overrides,
+    events: Receiver<Event>,
+    sender: Sender<Event>,
+    socket: PathBuf,
+    timer: Timer,
+    pause: Option<Pause>,
+    /// Kept alive for the watches to stay active.
+    watchers: Vec<RecommendedWatcher>,
+    stop: bool,
+}
+
+impl Daemon {
+    /// Sets up the sources of events: the control socket, the signals and
+    /// the file watches. The schedule follows the given clock.
+    fn start(settings: Settings, overrides: Overrides, clock: Box<dyn Clock>) -> Result<Self> {
+        let (sender, events) = mpsc::channel();
+        let socket = get_socket_path()?;
+        // Left behind by a daemon that did not stop cleanly.
+        if socket.exists() {
+            fs::remove_file(&socket)?;
+        }
+        let listener = UnixListener::bind(&socket)?;
+        let control = sender.clone();
+        thread::spawn(move || listen(&listener, &control));
+
+        let mut signals = Signals::new([SIGTERM, SIGINT, SIGHUP, SIGUSR1, SIGUSR2])?;
+        let signal = sender.clone();
+        thread::spawn(move || {
+            for number in signals.forever() {
+                if signal.send(Event::Signal(number)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut timer = Timer::new(clock);
+        let pause = get_pause().unwrap_or_else(|err| {
+            error!("{err}");
+            None
+        });
+        if let Some(pause) = pause {
+            match pause
+                .since
+                .and_then(|since| DateTime::from_timestamp(since, 0))
+            {
+                Some(since) => timer.pause_since(since),
+                None => timer.pause(),
+            }
+        }
+
+        let mut daemon = Self {
+            settings,
+            overrides,
+            events,
+            sender,
+            socket,
+            timer,
+            pause,
+            watchers: Vec::new(),
+            stop: false,
+        };
+        daemon.watch();
+        Ok(daemon)
+    }
+
+    /// Handles the events until the daemon is asked to stop.
+    fn run(mut self) {
+        info!("started");
+        if let Some((deadline, interval)) = self.restore_schedule() {
+            self.timer.restore(deadline, interval);
+            self.save_deadline();
+        }
+
+        while !self.stop {
+            self.tick();
+            match self.events.recv_timeout(self.get_timeout()) {
+                Ok(event) => self.handle(event),
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        if let Err(err) = fs::remove_file(&self.socket) {
+            error!("failed to remove {}: {err}", self.socket.display());
+        }
+    }
+
+    fn handle(&mut self, event: Event) {
+        match event {
+            Event::Signal(signal) => self.handle_signal(signal),
+            Event::Control(command, reply) => {
+                debug!("received command {command:?}");
+                let result = self.execute(command);
+                if let Err(err) = &result {
+                    error!("{err}");
+                }
+                let _ = reply.send(result);
+            }
+            Event::ConfigChanged => self.reload(),
+            Event::LibraryChanged => self.check_library(),
+        }
+    }
+
+    /// - SIGUSR1 changes the wallpaper now.
+    /// - SIGUSR2 pauses or resumes the slideshow.
+    /// - SIGHUP reloads the configuration and rescans the wallpaper directory.
+    /// - SIGTERM and SIGINT stop the daemon.
+    fn handle_signal(&mut self, signal: i32) {
+        let command = match signal {
+            SIGUSR1 => Command::Next,
+            SIGUSR2 if self.pause.is_some() => Command::Resume,
+            SIGUSR2 => Command::Pause(None),
+            SIGHUP => {
+                info!("received SIGHUP, reloading");
+                self.reload();
+                self.watch();
+                self.check_library();
+                return;
+            }
+            _ => {
+                info!("received signal {signal}, stopping");
+                self.stop = true;
+                return;
+            }
+        };
+
+        if let Err(err) = self.execute(command) {
+            error!("{err}");
+        }
+    }
+
+    /// Changes the wallpaper if the current one has been removed.
+    fn check_library(&mut self) {
+        // The current wallpaper cannot be read once its file is gone.
+        if get_current_wallpaper().is_err() && self.pause.is_none() {
+            self.change("the current wallpaper was removed");
+        }
+    }
+
+    fn execute(&mut self, command: Command) -> Result<()> {
+        match command {
+            Command::Next => {
+                if self.pause.is_some() {
+                    info!("skipped toggle: the slideshow is paused");
+                } else {
+                    self.change("toggled");
+                }
+            }
+            Command::Set(path) => {
+                let mut wallpaper = get_first_image(&self.settings, PathBuf::from(&path))?;
+                let path = wallpaper.to_string();
+                info!("changing wallpaper to {path}: set with --set");
+                update_wallpaper(&self.settings, &path)?;
+                self.wallpaper_changed(&path);
+                let deadline = self.get_deadline(&mut wallpaper);
+                self.schedule(deadline);
+            }
+            Command::Pause(until) => {
+                self.pause = Some(pause(until)?);
+                self.timer.pause();
+                info!("paused");
+            }
+            Command::Resume => {
+                resume()?;
+                self.resume();
+            }
+            Command::Reload => self.reload(),
+        }
+        Ok(())
+    }
+
+    /// Checks the timers: a suspension of the system, the end of a timed
+    /// pause and the time of the next change.
+    fn tick(&mut self) {
+        if let Some(suspended) = self.timer.check_wake(self.settings.on_wake) {
+            info!(
+                "woke up after {} seconds of suspension",
+                suspended.num_seconds()
+            );
+            self.save_deadline();
+        }
+
+        if let Some(Pause {
+            until: Some(until), ..
+        }) = self.pause
+        {
+            if until <= self.timer.now().timestamp() {
+                if let Err(err) = resume() {
+                    error!("{err}");
+                }
+                self.resume();
+            }
+        }
+
+        if self.timer.is_due() {
+            self.change("timer expired");
+        }
+    }
+
+    /// How long to wait for an event before the timers have to be checked.
+    fn get_timeout(&self) -> Duration {
+        let pause_end = self
+            .pause
+            .and_then(|pause| pause.until)
+            .and_then(|until| DateTime::from_timestamp(until, 0));
+        self.timer.get_timeout(pause_end)
+    }
+
+    /// Ends the pause, the time spent paused is added to the current interval.
+    fn resume(&mut self) {
+        self.pause = None;
+        if self.timer.resume() {
+            self.save_deadline();
+            info!("resumed");
+        }
+    }
+
+    /// Changes to the next wallpaper and plans the following change.
+    fn change(&mut self, reason: &str) {
+        let retry_time =
+            TimeDelta::seconds(i64::try_from(self.settings.sleep_time).unwrap_or(i64::MAX));
+
+        let deadline = match peek_next_wallpaper(&self.settings) {
+            Ok(mut wallpaper) => {
+                let path = wallpaper.to_string();
+                info!("changing wallpaper to {path}: {reason}");
+                if let Err(err) = update_wallpaper(&self.settings, &path) {
+                    error!("{err}");
+                    if let Err(err) = discard_wallpaper(&path) {
+                        error!("{err}");
+                    }
+                    self.timer.now() + retry_time
+                } else {
+                    self.wallpaper_changed(&path);
+                    self.get_deadline(&mut wallpaper)
+                }
+            }
+            Err(err) => {
+                error!("{err}");
+                self.timer.now() + retry_time
+            }
+        };
+        self.schedule(deadline);
+    }
+
+    fn wallpaper_changed(&self, path: &str) {
+        if let Err(err) = notify(&format!("STATUS=Wallpaper: {path}")) {
+            error!("failed to notify the service manager: {err}");
+        }
+    }
+
+    /// Plans the next change at the end of a new interval, starting now.
+    fn schedule(&mut self, deadline: DateTime<Utc>) {
+        self.timer.schedule(deadline);
+        self.save_deadline();
+    }
+
+    /// Stores the time of the next change in the state, so that a restarted
+    /// daemon keeps the same schedule.
+    fn save_deadline(&self) {
+        let deadline = self.timer.deadline();
+        debug!(
+            "next change in {} seconds",
+            (deadline - self.timer.now()).num_seconds()
+        );
+
+        if let Err(err) = set_next_change(Some(deadline.timestamp())) {
+            error!("{err}");
+        }
+    }
+
+    /// Gets the time at which the wallpaper that has just been set has to be
+    /// changed, according to its display time and the schedule.
+    fn get_deadline(&self, wallpaper: &mut ImagePath) -> DateTime<Utc> {
+        let sleep_time = match wallpaper.get_sleep_time(&self.settings) {
+            Ok(seconds) => seconds,
+            Err(err) => {
+                error!("{err}");
+                self.settings.sleep_time
+            }
+        };
+
+        let now = self.timer.now();
+        compute_next_change(
+            &self.settings,
+            now,
+            sleep_time,
+            wallpaper.is_animated(&self.settings),
+        )
+        .unwrap_or_else(|err| {
+            error!("{err}");
+            now + TimeDelta::seconds(i64::try_from(sleep_time).unwrap_or(i64::MAX))
+        })
+    }
+
+    /// Re-applies the current wallpaper if the previous run of the daemon had
+    /// planned a change that has not happened yet, and returns the time of
+    /// that change with the length of the interval of the wallpaper.
+    ///
+    /// The time left is at most one interval, in case it has been shortened
+    /// since, and is counted from the start of the pause if the slideshow is
+    /// paused, the timer being frozen from then.
+    ///
+    /// For animated wallpapers the current wallpaper is a frame, so the
+    /// animation resumes where it stopped.
+    fn restore_schedule(&self) -> Option<(DateTime<Utc>, TimeDelta)> {
+        let now = self.timer.now();
+        let start = self
+            .pause
+            .and_then(|pause| pause.since)
+            .and_then(|since| DateTime::from_timestamp(since, 0))
+            .unwrap_or(now);
+        let next_change = get_next_change().map_err(|err| error!("{err}")).ok()??;
+        let next_change = DateTime::from_timestamp(next_change, 0).filter(|t| *t > start)?;
+        let File::Image(mut current) = get_current_wallpaper().ok()? else {
+            return None;
+        };
+        let wallpaper = current.to_string();
+        let interval = self.get_deadline(&mut current) - now;
+        let left = (next_change - start).min(interval);
+
+        info!(
+            "restoring {wallpaper}: next change planned in {} seconds",
+            left.num_seconds()
+        );
+        if let Err(err) = restore_wallpaper(&self.settings, &wallpaper) {
+            error!("{err}");
+            return None;
+        }
+        self.wallpaper_changed(&wallpaper);
+        Some((start + left, interval))
+    }
+
+    /// Reads the configuration file again, the defaults are used if it has
+    /// been removed. The options given on the command line still take
+    /// precedence. The current settings are kept if the file is invalid.
+    fn reload(&mut self) {
+        let settings = match load_configuration() {
+            Ok(settings) => self.overrides.apply(settings),
+            Err(err) => {
+                error!("failed to reload the configuration: {err}");
+                return;
+            }
+        };
+
+        let schedule_changed = settings.sleep_time != self.settings.sleep_time
+            || settings.align != self.settings.align
+            || settings.cron != self.settings.cron;
+        let library_changed = settings.wallpaper_dir != self.settings.wallpaper_dir;
+        self.settings = settings;
+        forget_resolution();
+        info!("reloaded the configuration");
+
+        if schedule_changed {
+            if let Ok(File::Image(mut wallpaper)) = get_current_wallpaper() {
+                // Animations keep going at their own pace.
+                if !wallpaper.is_animated(&self.settings) {
+                    let deadline = self.get_deadline(&mut wallpaper);
+                    self.schedule(deadline);
+                }
+            }
+        }
+        if library_changed {
+            self.watch();
+        }
+    }
+
+    /// Watches the configuration file and the wallpaper directory.
+    fn watch(&mut self) {
+        self.watchers.clear();
+
+        let config_dir = dirs::config_dir().map(|dir| dir.join("wallshift"));
+        if let Some(config_dir) = config_dir.filter(|dir| dir.is_dir()) {
+            let sender = self.sender.clone();
+            let watcher = watch_path(&config_dir, RecursiveMode::NonRecursive, move |event| {
+                let config_changed = !matches!(event.kind, EventKind::Access(_))
+                    && event
+                        .paths
+                        .iter()
+                        .any(|path| path.file_name().is_some_and(|name| name == "config.yml"));
+                if config_changed {
+                    let _ = sender.send(Event::ConfigChanged);
+                }
+            });
+            self.watchers.extend(watcher);
+        }
+
+        let sender = self.sender.clone();
+        let watcher = watch_path(
+            Path::new(&self.settings.wallpaper_dir),
+            RecursiveMode::Recursive,
+            move |event| {
+                if matches!(
+                    event.kind,
+                    EventKind::Create(_) | EventKind::Remove(_) | EventKind::Modify(_)
+                ) {
+                    let _ = sender.send(Event::LibraryChanged);
+                }
+            },
+        );
+        self.watchers.extend(watcher);
+    }
+}
+
+/// Starts watching a path, the callback is called for every event.
+fn watch_path(
+    path: &Path,
+    mode: RecursiveMode,
+    callback: impl Fn(notify::Event) + Send + 'static,
+) -> Option<RecommendedWatcher> {
+    let watcher =
+        notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event) => callback(event),
+            Err(err) => warn!("file watch error: {err}"),
+        })
+        .and_then(|mut watcher| watcher.watch(path, mode).map(|()| watcher));
+
+    watcher
+        .map_err(|err| warn!("failed to watch {}: {err}", path.display()))
+        .ok()
+}
+
+/// Accepts the connections on the control socket, and forwards the commands
+/// to the daemon.
+fn listen(listener: &UnixListener, sender: &Sender<Event>) {
+    for stream in listener.incoming() {
+        let result = stream
+            .map_err(anyhow::Error::from)
+            .and_then(|stream| answer(stream, sender));
+        if let Err(err) = result {
+            warn!("control socket: {err}");
+        }
+    }
+}
+
+/// Reads a command from a client, and answers once the daemon handled it.
+fn answer(mut stream: UnixStream, sender: &Sender<Event>) -> Result<()> {
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    // Connections used to check that the daemon is running.
+    if line.is_empty() {
+        return Ok(());
+    }
+
+    let result = Command::parse(&line).and_then(|command| {
+        let (reply, result) = mpsc::channel();
+        sender
+            .send(Event::Control(command, reply))
+            .map_err(|_| anyhow!("the daemon is stopping"))?;
+        result
+            .recv()
+            .map_err(|_| anyhow!("the daemon is stopping"))?
+    });
+
+    match result {
+        Ok(()) => writeln!(stream, "ok")?,
+        Err(err) => writeln!(stream, "error {err}")?,
+    }
+    Ok(())
+}
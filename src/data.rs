@@ -1,82 +1,331 @@
 use serde_derive::{Deserialize, Serialize};
-use std::{fs, path::Path};
+use serde_yaml::{Mapping, Value};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 
 use anyhow::{anyhow, Result};
+use log::warn;
 
-use crate::path::File;
+use crate::{atomic, metadata::TagFilter, path::File, runtime::get_session_id};
 
-/// Returns the path to the current wallpaper information file
-fn get_wallpaper_info_path() -> Result<String> {
+/// Returns the directory where wallshift stores its data
+fn get_data_dir() -> Result<PathBuf> {
     Ok(dirs::data_local_dir()
         .ok_or(anyhow!("failed to get local data directory"))?
-        .join("wallshift")
-        .join(".current_wallpaper.yaml")
-        .to_str()
-        .ok_or(anyhow!("failed to convert wallpaper info path to str"))?
-        .to_owned())
+        .join("wallshift"))
+}
+
+/// Returns the directory holding the information file of every session
+fn get_sessions_dir() -> Result<PathBuf> {
+    Ok(get_data_dir()?.join("sessions"))
+}
+
+/// Returns the path to the current wallpaper information file of a session
+fn get_wallpaper_info_path(session: &str) -> Result<PathBuf> {
+    Ok(get_sessions_dir()?.join(format!("{session}.yaml")))
+}
+
+/// Returns the path to the information file used before each session had its
+/// own, it is used as the initial state of new sessions.
+fn get_legacy_wallpaper_info_path() -> Result<PathBuf> {
+    Ok(get_data_dir()?.join(".current_wallpaper.yaml"))
+}
+
+/// Version of the state format written by this version of wallshift.
+const STATE_VERSION: u64 = 2;
+
+/// Migrations of the state format, the one at index `n` converts a state of
+/// version `n` into a state of version `n + 1`.
+const MIGRATIONS: [fn(Mapping) -> Mapping; STATE_VERSION as usize] =
+    [migrate_v0_to_v1, migrate_v1_to_v2];
+
+/// Version 0 is the original, unversioned, format.
+fn migrate_v0_to_v1(state: Mapping) -> Mapping {
+    state
+}
+
+/// Version 2 replaces the `on` flag by an optional pause.
+fn migrate_v1_to_v2(mut state: Mapping) -> Mapping {
+    let on = state
+        .remove("on")
+        .and_then(|on| on.as_bool())
+        .unwrap_or(true);
+    if !on {
+        let mut pause = Mapping::new();
+        pause.insert("until".into(), Value::Null);
+        state.insert("pause".into(), Value::Mapping(pause));
+    }
+    state
+}
+
+/// The slideshow is paused, indefinitely or until a given time.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Pause {
+    /// Unix timestamp at which the slideshow resumes automatically.
+    pub until: Option<i64>,
+    /// Unix timestamp at which the pause started, the time left before the
+    /// next change is frozen from then, even across restarts of the daemon.
+    #[serde(default)]
+    pub since: Option<i64>,
+}
+
+impl Pause {
+    /// Checks if the pause is still ongoing.
+    #[must_use]
+    pub fn is_active(&self) -> bool {
+        self.until
+            .is_none_or(|until| until > chrono::Utc::now().timestamp())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct FileInfo {
+    version: u64,
     wallpaper: String,
-    on: bool,
+    #[serde(default)]
+    pause: Option<Pause>,
+    /// Unix timestamp of the last time the wallpaper was changed.
+    #[serde(default)]
+    last_change: Option<i64>,
+    /// Unix timestamp of the next change planned by the daemon.
+    #[serde(default)]
+    next_change: Option<i64>,
+    #[serde(default)]
+    tag_filter: Option<TagFilter>,
+    /// Wallpaper picked to come after the current one, so that `--get` shows
+    /// the one the next change will use.
+    #[serde(default)]
+    up_next: Option<String>,
+    /// Wallpapers lined up with `queue add`, shown before any other.
+    #[serde(default)]
+    queue: Vec<String>,
 }
 
 impl Default for FileInfo {
     fn default() -> Self {
         Self {
+            version: STATE_VERSION,
             wallpaper: String::default(),
-            on: true,
+            pause: None,
+            last_change: None,
+            next_change: None,
+            tag_filter: None,
+            up_next: None,
+            queue: Vec::new(),
         }
     }
 }
 
-/// Reads the YAML file and returns a `FileInfo` struct
+/// Parses a state file, migrating it to the current format if needed.
+fn parse_state(contents: &str) -> Result<FileInfo> {
+    let mut state: Mapping = serde_yaml::from_str(contents)?;
+
+    let version = match state.get("version") {
+        Some(version) => version
+            .as_u64()
+            .ok_or(anyhow!("invalid state version {version:?}"))?,
+        None => 0,
+    };
+    if version > STATE_VERSION {
+        return Err(anyhow!(
+            "the state has version {version}, which is newer than the supported one ({STATE_VERSION})"
+        ));
+    }
+
+    for migration in &MIGRATIONS[version as usize..] {
+        state = migration(state);
+    }
+    state.insert("version".into(), STATE_VERSION.into());
+
+    Ok(serde_yaml::from_value(Value::Mapping(state))?)
+}
+
+/// Takes the advisory lock of the state of a session, shared for reading or
+/// exclusive for modifying it. It is released when the file is dropped.
+///
+/// A separate lock file is used as the state file itself is replaced on
+/// every write.
+fn lock_session(session: &str, exclusive: bool) -> Result<fs::File> {
+    fs::create_dir_all(get_sessions_dir()?)?;
+
+    let lock = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(get_sessions_dir()?.join(format!(".{session}.lock")))?;
+    if exclusive {
+        lock.lock()?;
+    } else {
+        lock.lock_shared()?;
+    }
+    Ok(lock)
+}
+
+/// Reads the YAML file of the current session and returns a `FileInfo` struct
 fn read_config() -> Result<FileInfo> {
-    let path = dirs::data_local_dir()
-        .ok_or(anyhow!("failed to get local data directory"))?
-        .join("wallshift");
-    std::fs::create_dir_all(path)?;
+    read_session(&get_session_id())
+}
 
-    let path_str = get_wallpaper_info_path()?;
+/// Reads the YAML file of a session and returns a `FileInfo` struct
+fn read_session(session: &str) -> Result<FileInfo> {
+    let _lock = lock_session(session, false)?;
+    read_session_unlocked(session)
+}
 
-    let path = Path::new(&path_str);
+/// Reads the state of a session, the caller must hold its lock.
+///
+/// A corrupt state is reported, moved aside (with a `.corrupt` extension)
+/// and replaced by the default one, so that wallshift keeps working.
+fn read_session_unlocked(session: &str) -> Result<FileInfo> {
+    let path = get_wallpaper_info_path(session)?;
+    let legacy_path = get_legacy_wallpaper_info_path()?;
 
-    let config: FileInfo = if path.exists() {
-        let contents = fs::read_to_string(path)?;
-        serde_yaml::from_str(&contents)?
+    let path = if path.exists() {
+        path
+    } else if legacy_path.exists() {
+        legacy_path
     } else {
-        FileInfo::default()
+        return Ok(FileInfo::default());
     };
 
-    Ok(config)
+    let contents = fs::read_to_string(&path)?;
+    match parse_state(&contents) {
+        Ok(config) => Ok(config),
+        Err(err) => {
+            warn!(
+                "the state file {} is corrupt, using the default state: {err}",
+                path.display()
+            );
+            // Another process may have already moved it.
+            let _ = fs::rename(&path, path.with_extension("yaml.corrupt"));
+            Ok(FileInfo::default())
+        }
+    }
 }
 
-/// Writes the `FileInfo` struct into the YAML file
+/// Writes the `FileInfo` struct into the YAML file of the current session,
+/// the caller must hold its lock. It is never left half written.
 fn write_config(config: &FileInfo) -> Result<()> {
-    let path = get_wallpaper_info_path()?;
-    let yaml = serde_yaml::to_string(config)?;
-    fs::write(path, yaml)?;
-    Ok(())
+    let path = get_wallpaper_info_path(&get_session_id())?;
+    atomic::write(&path, serde_yaml::to_string(config)?)
 }
 
+/// Modifies the state of the current session, while holding its lock.
 fn modify_config<F>(f: F) -> Result<()>
 where
-    F: Fn(FileInfo) -> FileInfo,
+    F: FnOnce(FileInfo) -> FileInfo,
 {
-    let config = read_config()?;
+    let session = get_session_id();
+    let _lock = lock_session(&session, true)?;
+
+    let config = read_session_unlocked(&session)?;
     write_config(&f(config))?;
     Ok(())
 }
-/// Saves the path to the current wallpaper on the right file
+
+/// Saves the path to the current wallpaper on the right file. If it is the
+/// first queued wallpaper (or a frame of it), it leaves the queue.
 pub fn save_wallpaper(wallpaper: &str) -> Result<()> {
-    let path = dirs::data_local_dir()
-        .ok_or(anyhow!("failed to get local data directory"))?
-        .join("wallshift");
-    std::fs::create_dir_all(path)?;
+    modify_config(|mut info| {
+        if info
+            .queue
+            .first()
+            .is_some_and(|queued| Path::new(wallpaper).starts_with(queued))
+        {
+            info.queue.remove(0);
+        }
+        FileInfo {
+            wallpaper: wallpaper.to_string(),
+            last_change: Some(chrono::Utc::now().timestamp()),
+            // It was picked to follow the previous wallpaper.
+            up_next: None,
+            ..info
+        }
+    })
+}
+
+/// Gets the unix timestamp of the next change planned by the daemon.
+pub fn get_next_change() -> Result<Option<i64>> {
+    read_config().map(|c| c.next_change)
+}
+
+/// Stores the unix timestamp of the next change planned by the daemon, so
+/// that a restarted daemon can keep the same schedule.
+pub fn set_next_change(next_change: Option<i64>) -> Result<()> {
+    modify_config(|info| FileInfo {
+        next_change,
+        ..info
+    })
+}
+
+/// Gets the wallpaper picked to be the next one, if any.
+pub fn get_up_next() -> Result<Option<String>> {
+    read_config().map(|c| c.up_next)
+}
 
+/// Stores the wallpaper picked to be the next one.
+pub fn set_up_next(up_next: Option<String>) -> Result<()> {
     modify_config(|info| FileInfo {
-        wallpaper: wallpaper.to_string(),
+        up_next: up_next.clone(),
+        ..info
+    })
+}
+
+/// Gets the queued wallpapers, in order.
+pub fn get_queue() -> Result<Vec<String>> {
+    read_config().map(|c| c.queue)
+}
+
+/// Adds a wallpaper at the end of the queue. Like every change of the queue,
+/// the wallpaper picked to be the next one is forgotten.
+pub fn push_queue(wallpaper: &str) -> Result<()> {
+    modify_config(|mut info| {
+        info.queue.push(wallpaper.to_owned());
+        // The queue comes before the wallpaper that was picked.
+        info.up_next = None;
+        info
+    })
+}
+
+/// Removes the queued wallpapers matching `remove`, and returns how many
+/// were removed.
+pub fn remove_from_queue(remove: impl Fn(usize, &str) -> bool) -> Result<usize> {
+    let mut removed = 0;
+    modify_config(|mut info| {
+        let queue = std::mem::take(&mut info.queue);
+        let before = queue.len();
+        info.queue = queue
+            .into_iter()
+            .enumerate()
+            .filter(|(index, wallpaper)| !remove(*index, wallpaper))
+            .map(|(_, wallpaper)| wallpaper)
+            .collect();
+        removed = before - info.queue.len();
+        info.up_next = None;
+        info
+    })?;
+    Ok(removed)
+}
+
+/// Forgets a wallpaper that failed to be set: it leaves the queue, with the
+/// animation it is a frame of, and another wallpaper will be picked next.
+pub fn discard_wallpaper(wallpaper: &str) -> Result<()> {
+    modify_config(|mut info| {
+        info.queue
+            .retain(|queued| !Path::new(wallpaper).starts_with(queued));
+        FileInfo {
+            up_next: None,
+            ..info
+        }
+    })
+}
+
+pub fn clear_queue() -> Result<()> {
+    modify_config(|info| FileInfo {
+        queue: Vec::new(),
+        up_next: None,
         ..info
     })
 }
@@ -89,14 +338,154 @@ pub fn get_current_wallpaper() -> Result<File> {
         .map_err(|msg| anyhow!("failed to get current wallpaper: {msg}"))
 }
 
-pub fn is_on() -> Result<bool> {
-    read_config().map(|c| c.on)
+/// Checks if the slideshow is paused. A pause whose end has passed is over.
+pub fn is_paused() -> Result<bool> {
+    read_config().map(|c| c.pause.is_some_and(|pause| pause.is_active()))
+}
+
+/// Gets the ongoing pause of the slideshow, if any.
+pub fn get_pause() -> Result<Option<Pause>> {
+    read_config().map(|c| c.pause.filter(Pause::is_active))
+}
+
+/// Pauses the slideshow, until the given unix timestamp or indefinitely, and
+/// returns the pause. Pausing again only changes its end.
+pub fn pause(until: Option<i64>) -> Result<Pause> {
+    let mut paused = Pause::default();
+    modify_config(|info| {
+        let since = info
+            .pause
+            .filter(Pause::is_active)
+            .and_then(|pause| pause.since)
+            .unwrap_or_else(|| chrono::Utc::now().timestamp());
+        paused = Pause {
+            until,
+            since: Some(since),
+        };
+        FileInfo {
+            pause: Some(paused),
+            ..info
+        }
+    })?;
+    Ok(paused)
+}
+
+pub fn resume() -> Result<()> {
+    modify_config(|info| FileInfo {
+        pause: None,
+        ..info
+    })
+}
+
+/// Gets the tag filter set with `--tags`/`--exclude-tags`, if any.
+pub fn get_tag_filter() -> Result<Option<TagFilter>> {
+    read_config().map(|c| c.tag_filter)
+}
+
+/// Stores a tag filter, it will be used until it is cleared. The wallpaper
+/// picked to be the next one is forgotten, as it may not match it.
+pub fn set_tag_filter(filter: TagFilter) -> Result<()> {
+    modify_config(|info| FileInfo {
+        tag_filter: Some(filter.clone()),
+        up_next: None,
+        ..info
+    })
+}
+
+pub fn clear_tag_filter() -> Result<()> {
+    modify_config(|info| FileInfo {
+        tag_filter: None,
+        up_next: None,
+        ..info
+    })
 }
 
-pub fn set_off() -> Result<()> {
-    modify_config(|info| FileInfo { on: false, ..info })
+/// State of a session, as shown by `--sessions`.
+pub struct SessionInfo {
+    pub id: String,
+    pub wallpaper: String,
+    pub pause: Option<Pause>,
 }
 
-pub fn set_on() -> Result<()> {
-    modify_config(|info| FileInfo { on: true, ..info })
+/// Lists every session that has stored some state.
+pub fn list_sessions() -> Result<Vec<SessionInfo>> {
+    let dir = get_sessions_dir()?;
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut sessions = fs::read_dir(dir)?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            if path.extension()? != "yaml" {
+                return None;
+            }
+            let id = path.file_stem()?.to_str()?.to_owned();
+            let info = read_session(&id).ok()?;
+            Some(SessionInfo {
+                id,
+                wallpaper: info.wallpaper,
+                pause: info.pause.filter(Pause::is_active),
+            })
+        })
+        .collect::<Vec<_>>();
+    sessions.sort_by(|a, b| a.id.cmp(&b.id));
+
+    Ok(sessions)
+}
+
+/// Removes the stored state of a session.
+pub fn remove_session(session: &str) -> Result<()> {
+    let lock = lock_session(session, true)?;
+    fs::remove_file(get_wallpaper_info_path(session)?)?;
+    drop(lock);
+    fs::remove_file(get_sessions_dir()?.join(format!(".{session}.lock")))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrate_paused_v0_state() {
+        let info = parse_state("wallpaper: /wallpapers/a.png\non: false\n").unwrap();
+        assert_eq!(info.version, STATE_VERSION);
+        assert_eq!(info.wallpaper, "/wallpapers/a.png");
+        assert!(matches!(info.pause, Some(Pause { until: None, .. })));
+    }
+
+    #[test]
+    fn migrate_running_v0_state() {
+        let info = parse_state("wallpaper: /wallpapers/a.png\non: true\n").unwrap();
+        assert!(info.pause.is_none());
+    }
+
+    #[test]
+    fn keep_current_state() {
+        let contents = serde_yaml::to_string(&FileInfo {
+            pause: Some(Pause {
+                until: Some(42),
+                since: Some(12),
+            }),
+            queue: vec!["/wallpapers/b.png".to_owned()],
+            ..FileInfo::default()
+        })
+        .unwrap();
+        let info = parse_state(&contents).unwrap();
+        assert!(matches!(
+            info.pause,
+            Some(Pause {
+                until: Some(42),
+                since: Some(12)
+            })
+        ));
+        assert_eq!(info.queue, ["/wallpapers/b.png"]);
+    }
+
+    #[test]
+    fn reject_newer_state() {
+        let contents = format!("version: {}\nwallpaper: /a.png\n", STATE_VERSION + 1);
+        assert!(parse_state(&contents).is_err());
+    }
 }
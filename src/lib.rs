@@ -1,6 +1,21 @@
+pub mod atomic;
 pub mod cli;
 pub mod configuration;
+pub mod control;
+pub mod daemon;
 pub mod data;
+pub mod lockscreen;
+pub mod logging;
+pub mod metadata;
+pub mod palette;
 pub mod path;
+pub mod pipeline;
+pub mod process;
+pub mod runtime;
+pub mod schedule;
+pub mod setter;
 pub mod setup;
+pub mod sync;
+pub mod systemd;
+pub mod template;
 pub mod wallpaper;
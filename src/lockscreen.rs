@@ -0,0 +1,262 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Condvar, Mutex, OnceLock},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use log::{debug, error, info};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    atomic,
+    configuration::{expand_home, Settings},
+    path::is_later_frame,
+    pipeline::{get_cache_dir, prepare_lockscreen},
+    process,
+};
+
+/// The program showing the lock screen.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LockscreenTool {
+    /// Updated with `betterlockscreen -u`.
+    #[default]
+    Betterlockscreen,
+    /// The `image` option of its configuration file is updated.
+    Swaylock,
+    /// i3lock only reads PNG images at the screen resolution, wallshift keeps
+    /// one up to date at the `output` path, to be used with `i3lock -i`.
+    I3lock,
+    /// A shell command, in which `{{wallpaper}}` is replaced by the path of
+    /// the image.
+    Custom,
+}
+
+/// The `lockscreen` section of the configuration.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Lockscreen {
+    pub tool: LockscreenTool,
+    /// Command run by the `custom` tool.
+    #[serde(default)]
+    pub command: Option<String>,
+    /// Configuration file of swaylock, `~/.config/swaylock/config` by default.
+    #[serde(default)]
+    pub swaylock_config: Option<String>,
+    /// Radius of the blur applied to the wallpaper, none if 0.
+    #[serde(default)]
+    pub blur: f32,
+    /// How much the wallpaper is darkened, from 0 (not at all) to 1 (black).
+    #[serde(default)]
+    pub dim: f32,
+    /// Where the image processed by wallshift is written,
+    /// `$XDG_CACHE_HOME/wallshift/lockscreen.png` by default.
+    #[serde(default)]
+    pub output: Option<String>,
+    /// Also update the lock screen with every frame of animated wallpapers,
+    /// instead of only their first frame.
+    #[serde(default)]
+    pub frames: bool,
+}
+
+impl Lockscreen {
+    /// Checks if the wallpaper has to be converted before being given to the
+    /// lock screen.
+    fn needs_processing(&self) -> bool {
+        self.tool == LockscreenTool::I3lock || self.blur > 0.0 || self.dim > 0.0
+    }
+
+    fn get_output(&self) -> Result<PathBuf> {
+        match &self.output {
+            Some(output) => Ok(PathBuf::from(expand_home(output))),
+            None => Ok(get_cache_dir()?.join("lockscreen.png")),
+        }
+    }
+}
+
+/// A lock screen update waiting to be done.
+struct Request {
+    lockscreen: Lockscreen,
+    settings: Settings,
+    wallpaper: String,
+}
+
+#[derive(Default)]
+struct State {
+    /// Only the latest request is kept, older ones are outdated.
+    pending: Option<Request>,
+    busy: bool,
+}
+
+/// Updates the lock screen in the background, since it can take several
+/// seconds (betterlockscreen pre-renders blurred images, wallshift may have
+/// to convert the wallpaper).
+#[derive(Default)]
+struct Worker {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+static WORKER: OnceLock<&'static Worker> = OnceLock::new();
+
+impl Worker {
+    fn get() -> &'static Self {
+        WORKER.get_or_init(|| {
+            let worker: &'static Self = Box::leak(Box::default());
+            thread::spawn(|| worker.run());
+            worker
+        })
+    }
+
+    fn run(&self) {
+        loop {
+            let request = {
+                let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+                while state.pending.is_none() {
+                    state = self
+                        .changed
+                        .wait(state)
+                        .unwrap_or_else(|err| err.into_inner());
+                }
+                state.busy = true;
+                state.pending.take()
+            };
+
+            if let Some(request) = request {
+                update(&request);
+            }
+
+            self.state
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .busy = false;
+            self.changed.notify_all();
+        }
+    }
+}
+
+fn update(request: &Request) {
+    info!("updating the lock screen with {}", request.wallpaper);
+    if let Err(err) = apply(&request.lockscreen, &request.settings, &request.wallpaper) {
+        error!("failed to update the lock screen: {err}");
+    }
+}
+
+/// Processes the wallpaper if needed and hands it to the lock screen.
+fn apply(lockscreen: &Lockscreen, settings: &Settings, wallpaper: &str) -> Result<()> {
+    let timeout = Duration::from_secs(settings.command_timeout);
+    let image = if lockscreen.needs_processing() {
+        let output = lockscreen.get_output()?;
+        prepare_lockscreen(
+            settings,
+            wallpaper,
+            lockscreen.blur,
+            lockscreen.dim,
+            &output,
+        )?;
+        output.to_string_lossy().into_owned()
+    } else {
+        wallpaper.to_owned()
+    };
+
+    match lockscreen.tool {
+        LockscreenTool::Betterlockscreen => process::run(
+            Command::new("betterlockscreen").arg("-u").arg(&image),
+            timeout,
+        ),
+        LockscreenTool::Swaylock => {
+            let config = lockscreen.swaylock_config.as_ref().map_or_else(
+                || {
+                    dirs::config_dir()
+                        .ok_or(anyhow!("failed to get config directory"))
+                        .map(|dir| dir.join("swaylock").join("config"))
+                },
+                |config| Ok(PathBuf::from(expand_home(config))),
+            )?;
+            set_swaylock_image(&config, &image)
+        }
+        // The image is already where i3lock expects it.
+        LockscreenTool::I3lock => Ok(()),
+        LockscreenTool::Custom => {
+            let command = lockscreen
+                .command
+                .as_ref()
+                .ok_or(anyhow!("the custom lock screen has no `command`"))?;
+            let command = command.replace("{{wallpaper}}", &shell_quote(&image));
+            process::run(Command::new("sh").arg("-c").arg(command), timeout)
+        }
+    }
+}
+
+/// Replaces the `image` option of the swaylock configuration file, keeping
+/// the other options.
+fn set_swaylock_image(config: &Path, image: &str) -> Result<()> {
+    let contents = match fs::read_to_string(config) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(anyhow!("failed to read {}: {err}", config.display())),
+    };
+
+    let mut lines = contents
+        .lines()
+        .filter(|line| !line.trim_start().starts_with("image="))
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    lines.push(format!("image={image}"));
+
+    if let Some(parent) = config.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    atomic::write(config, lines.join("\n") + "\n")
+}
+
+/// Quotes a string for `sh`.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Queues an update of the lock screen with the given wallpaper, replacing
+/// the update that has not started yet, if any.
+///
+/// The frames of animated wallpapers, other than the first one, are skipped
+/// unless `frames` is enabled.
+pub fn update_lockscreen(settings: &Settings, wallpaper: &str) {
+    let Some(lockscreen) = settings.get_lockscreen() else {
+        return;
+    };
+    if !lockscreen.frames && is_later_frame(settings, wallpaper) {
+        debug!("skipped the lock screen update for the frame {wallpaper}");
+        return;
+    }
+
+    let worker = Worker::get();
+    let mut state = worker.state.lock().unwrap_or_else(|err| err.into_inner());
+    let request = Request {
+        lockscreen,
+        settings: settings.clone(),
+        wallpaper: wallpaper.to_owned(),
+    };
+    if let Some(outdated) = state.pending.replace(request) {
+        debug!("dropped the lock screen update for {}", outdated.wallpaper);
+    }
+    worker.changed.notify_all();
+}
+
+/// Waits for the queued lock screen updates to be done, for processes that
+/// exit right after changing the wallpaper.
+pub fn wait_lockscreen() {
+    let Some(worker) = WORKER.get() else {
+        return;
+    };
+
+    let mut state = worker.state.lock().unwrap_or_else(|err| err.into_inner());
+    while state.pending.is_some() || state.busy {
+        state = worker
+            .changed
+            .wait(state)
+            .unwrap_or_else(|err| err.into_inner());
+    }
+}
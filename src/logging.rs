@@ -0,0 +1,177 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    str::FromStr,
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, Result};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::configuration::Settings;
+
+/// Number of rotated log files kept (`wallshift.log.1`, `wallshift.log.2`...).
+const ROTATED_FILES: usize = 3;
+
+/// How the messages written to stderr are formatted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// `Error, message`, meant to be read by a user.
+    /// Only warnings and errors are written, unless a verbose level is set.
+    Plain,
+    /// `<3>message`, the syslog priority prefix understood by journald.
+    Journald,
+}
+
+/// The log file, rotated once it gets bigger than `max_size` bytes.
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    max_size: u64,
+}
+
+impl LogFile {
+    fn open(path: PathBuf, max_size: u64) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+
+        Ok(Self {
+            path,
+            file,
+            max_size,
+        })
+    }
+
+    fn write(&mut self, line: &str) -> Result<()> {
+        if self.file.metadata()?.len() >= self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{line}")?;
+        Ok(())
+    }
+
+    /// Shifts every log file by one, dropping the oldest one, and starts a
+    /// new file.
+    fn rotate(&mut self) -> Result<()> {
+        let rotated = |n: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{n}"));
+            PathBuf::from(path)
+        };
+
+        for n in (1..ROTATED_FILES).rev() {
+            if rotated(n).exists() {
+                fs::rename(rotated(n), rotated(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, rotated(1))?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        Ok(())
+    }
+}
+
+struct Logger {
+    format: LogFormat,
+    level: LevelFilter,
+    file: Option<Mutex<LogFile>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        match self.format {
+            LogFormat::Plain if record.level() <= Level::Warn || self.level > LevelFilter::Info => {
+                eprintln!("{}, {}", level_name(record.level()), record.args());
+            }
+            LogFormat::Plain => (),
+            LogFormat::Journald => eprintln!("<{}>{}", priority(record.level()), record.args()),
+        }
+
+        if let Some(file) = &self.file {
+            let line = format!(
+                "{} {:<5} [{}] {}",
+                chrono::Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%:z"),
+                record.level(),
+                std::process::id(),
+                record.args()
+            );
+            if let Ok(mut file) = file.lock() {
+                if let Err(err) = file.write(&line) {
+                    eprintln!("Error, failed to write to the log file: {err}");
+                }
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(Ok(mut file)) = self.file.as_ref().map(Mutex::lock) {
+            let _ = file.file.flush();
+        }
+    }
+}
+
+const fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::Error => "Error",
+        Level::Warn => "Warning",
+        Level::Info => "Info",
+        Level::Debug => "Debug",
+        Level::Trace => "Trace",
+    }
+}
+
+/// Syslog priority of each level, see `sd-daemon(3)`.
+const fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Returns the path to the log file.
+pub fn get_log_path() -> Result<PathBuf> {
+    Ok(dirs::state_dir()
+        .or_else(dirs::data_local_dir)
+        .ok_or(anyhow!("failed to get state directory"))?
+        .join("wallshift")
+        .join("wallshift.log"))
+}
+
+/// Sets up the logger used by the whole program. Messages at or above the
+/// configured level are written, with a timestamp, to the log file.
+pub fn init_logger(format: LogFormat, settings: &Settings) {
+    let level = LevelFilter::from_str(&settings.log_level).unwrap_or_else(|_| {
+        eprintln!("Warning, invalid log level `{}`", settings.log_level);
+        LevelFilter::Info
+    });
+
+    let file = get_log_path()
+        .and_then(|path| LogFile::open(path, settings.log_max_size))
+        .map_err(|err| eprintln!("Warning, failed to open the log file: {err}"))
+        .ok()
+        .map(Mutex::new);
+
+    let logger = Box::new(Logger {
+        format,
+        level,
+        file,
+    });
+    if log::set_boxed_logger(logger).is_ok() {
+        log::set_max_level(level);
+    }
+}
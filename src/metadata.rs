@@ -0,0 +1,237 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use log::error;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{configuration::Settings, setter::Fit};
+
+/// Name of the file, at the root of the wallpaper directory, that can hold
+/// the metadata of every wallpaper.
+pub const LIBRARY_FILE: &str = "library.yml";
+
+/// Optional information about a wallpaper.
+///
+/// It can be stored in a sidecar file next to the wallpaper, named after it
+/// with an extra `.yml` extension (`forest.png.yml`), or in the `library.yml`
+/// file at the root of the wallpaper directory, indexed by the path of the
+/// wallpaper relative to that directory. When both exist the sidecar wins.
+///
+/// The metadata of an animated wallpaper is set on its folder
+/// (`wallpaper_dir/animation.yml` or the `animation` entry of the library)
+/// and applies to all its frames.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Metadata {
+    /// Number of seconds the wallpaper is displayed, instead of `sleep_time`.
+    pub duration: Option<u64>,
+    /// How the wallpaper is placed on the screen.
+    pub fit: Option<Fit>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Name of the output the wallpaper should be displayed on. It is not
+    /// used yet, as wallpapers are set on every monitor at once.
+    pub monitor: Option<String>,
+    /// Relative probability of the wallpaper being picked, 1 by default.
+    pub weight: Option<u32>,
+    /// Disabled wallpapers are never picked.
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+impl Metadata {
+    /// Combines two entries, the values of `other` taking precedence.
+    fn merge(self, other: Self) -> Self {
+        let mut tags = self.tags;
+        for tag in other.tags {
+            if !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+
+        Self {
+            duration: other.duration.or(self.duration),
+            fit: other.fit.or(self.fit),
+            tags,
+            monitor: other.monitor.or(self.monitor),
+            weight: other.weight.or(self.weight),
+            disabled: self.disabled || other.disabled,
+        }
+    }
+
+    #[must_use]
+    pub fn weight(&self) -> u32 {
+        if self.disabled {
+            0
+        } else {
+            self.weight.unwrap_or(1)
+        }
+    }
+}
+
+/// The metadata of the wallpaper directory.
+pub struct Library {
+    root: PathBuf,
+    entries: HashMap<String, Metadata>,
+}
+
+impl Library {
+    /// Loads the `library.yml` file of the wallpaper directory, if any.
+    /// A malformed file is reported and ignored.
+    #[must_use]
+    pub fn load(settings: &Settings) -> Self {
+        let root = PathBuf::from(&settings.wallpaper_dir);
+        let entries = read_yaml(&root.join(LIBRARY_FILE)).unwrap_or_default();
+
+        Self { root, entries }
+    }
+
+    /// Gets the tags of a wallpaper: the ones in its metadata and, implicitly,
+    /// the names of the folders containing it (including itself if it is an
+    /// animated wallpaper).
+    #[must_use]
+    pub fn get_tags(&self, path: &Path) -> Vec<String> {
+        let mut tags = self.get(path).tags;
+        if let Ok(relative) = path.strip_prefix(&self.root) {
+            for ancestor in relative.ancestors().filter(|p| !p.as_os_str().is_empty()) {
+                if !self.root.join(ancestor).is_dir() {
+                    continue;
+                }
+                if let Some(name) = ancestor.file_name().and_then(|n| n.to_str()) {
+                    if !tags.iter().any(|t| t == name) {
+                        tags.push(name.to_owned());
+                    }
+                }
+            }
+        }
+        tags
+    }
+
+    /// Gets the metadata of a wallpaper, an animated wallpaper or one of its
+    /// frames. Wallpapers outside of the wallpaper directory have none.
+    #[must_use]
+    pub fn get(&self, path: &Path) -> Metadata {
+        let Ok(relative) = path.strip_prefix(&self.root) else {
+            return Metadata::default();
+        };
+
+        let mut ancestors = relative
+            .ancestors()
+            .filter(|p| !p.as_os_str().is_empty())
+            .collect::<Vec<_>>();
+        ancestors.reverse();
+
+        ancestors
+            .iter()
+            .enumerate()
+            .fold(Metadata::default(), |metadata, (depth, ancestor)| {
+                let mut metadata = match ancestor.to_str().and_then(|a| self.entries.get(a)) {
+                    Some(entry) => metadata.merge(entry.clone()),
+                    None => metadata,
+                };
+                // Sidecars inside animated wallpapers would be mistaken for frames.
+                if depth == 0 {
+                    let mut sidecar = self.root.join(ancestor).into_os_string();
+                    sidecar.push(".yml");
+                    if let Some(entry) = read_yaml(Path::new(&sidecar)) {
+                        metadata = metadata.merge(entry);
+                    }
+                }
+                metadata
+            })
+    }
+}
+
+/// Restricts the wallpapers that can be picked according to their tags.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TagFilter {
+    /// If not empty, wallpapers must have at least one of these tags.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Wallpapers with any of these tags are never picked.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+impl TagFilter {
+    #[must_use]
+    pub fn matches(&self, tags: &[String]) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|t| tags.contains(t)))
+            && !self.exclude.iter().any(|t| tags.contains(t))
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+}
+
+/// Gets the metadata of a single wallpaper.
+#[must_use]
+pub fn get_metadata(settings: &Settings, path: &Path) -> Metadata {
+    Library::load(settings).get(path)
+}
+
+/// Checks if the path is a metadata file rather than a wallpaper.
+#[must_use]
+pub fn is_metadata_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext == "yml" || ext == "yaml")
+}
+
+fn read_yaml<T: serde::de::DeserializeOwned>(path: &Path) -> Option<T> {
+    let contents = fs::read_to_string(path).ok()?;
+    serde_yaml::from_str(&contents)
+        .map_err(|err| error!("failed to read {}: {err}", path.display()))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|&tag| tag.to_owned()).collect()
+    }
+
+    #[test]
+    fn empty_filter_matches_everything() {
+        let filter = TagFilter::default();
+        assert!(filter.is_empty());
+        assert!(filter.matches(&[]));
+        assert!(filter.matches(&tags(&["nature"])));
+    }
+
+    #[test]
+    fn include_any_tag() {
+        let filter = TagFilter {
+            include: tags(&["nature", "city"]),
+            exclude: vec![],
+        };
+        assert!(filter.matches(&tags(&["city"])));
+        assert!(filter.matches(&tags(&["dark", "nature"])));
+        assert!(!filter.matches(&tags(&["dark"])));
+        assert!(!filter.matches(&[]));
+        assert!(!filter.matches(&tags(&["Nature"])));
+    }
+
+    #[test]
+    fn exclude_wins_over_include() {
+        let filter = TagFilter {
+            include: tags(&["nature"]),
+            exclude: tags(&["dark"]),
+        };
+        assert!(filter.matches(&tags(&["nature"])));
+        assert!(!filter.matches(&tags(&["nature", "dark"])));
+
+        let filter = TagFilter {
+            include: vec![],
+            exclude: tags(&["dark"]),
+        };
+        assert!(filter.matches(&[]));
+        assert!(filter.matches(&tags(&["nature"])));
+        assert!(!filter.matches(&tags(&["dark"])));
+    }
+}
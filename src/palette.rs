@@ -0,0 +1,191 @@
+use std::{fmt::Display, path::Path};
+
+use anyhow::{anyhow, Result};
+
+/// Number of distinct colors extracted from the wallpaper before the
+/// 16 color terminal palette is derived from them.
+const BASE_COLORS: usize = 8;
+
+/// Size of the thumbnail the colors are sampled from.
+const SAMPLE_SIZE: u32 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+
+    /// Relative luminance, only used to order the colors.
+    fn luminance(self) -> f32 {
+        0.2126 * f32::from(self.r) + 0.7152 * f32::from(self.g) + 0.0722 * f32::from(self.b)
+    }
+
+    /// Moves the color towards white by `amount` (between 0 and 1).
+    fn lighten(self, amount: f32) -> Self {
+        let mix = |c: u8| (f32::from(c) + (255.0 - f32::from(c)) * amount).round() as u8;
+        Self::new(mix(self.r), mix(self.g), mix(self.b))
+    }
+
+    /// Moves the color towards black by `amount` (between 0 and 1).
+    fn darken(self, amount: f32) -> Self {
+        let mix = |c: u8| (f32::from(c) * (1.0 - amount)).round() as u8;
+        Self::new(mix(self.r), mix(self.g), mix(self.b))
+    }
+
+    /// Hexadecimal representation without the leading `#`.
+    #[must_use]
+    pub fn strip(&self) -> String {
+        format!("{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+    }
+}
+
+impl Display for Color {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{}", self.strip())
+    }
+}
+
+/// A 16 color palette extracted from a wallpaper, plus the special colors
+/// most terminal emulators expect.
+#[derive(Debug, Clone)]
+pub struct Palette {
+    pub colors: [Color; 16],
+    pub background: Color,
+    pub foreground: Color,
+    pub cursor: Color,
+}
+
+impl Palette {
+    /// Extracts the palette of the image at the given path.
+    ///
+    /// The image is downscaled and its colors are reduced with a median cut,
+    /// the resulting colors are sorted from darkest to lightest and used to
+    /// build a terminal friendly palette (`color0` dark, `color7` light and
+    /// `color8`-`color15` as brighter variants).
+    pub fn from_image(path: &Path) -> Result<Self> {
+        let image = image::open(path)
+            .map_err(|err| anyhow!("failed to open {}: {err}", path.display()))?
+            .thumbnail(SAMPLE_SIZE, SAMPLE_SIZE)
+            .to_rgb8();
+
+        let pixels = image
+            .pixels()
+            .map(|p| Color::new(p[0], p[1], p[2]))
+            .collect::<Vec<_>>();
+
+        if pixels.is_empty() {
+            return Err(anyhow!("{} contains no pixels", path.display()));
+        }
+
+        let mut base = median_cut(pixels, BASE_COLORS);
+        base.sort_by(|a, b| a.luminance().total_cmp(&b.luminance()));
+        // Images with very few colors can yield less buckets than requested.
+        while base.len() < BASE_COLORS {
+            base.push(base[base.len() - 1]);
+        }
+
+        let mut colors = [Color::new(0, 0, 0); 16];
+        colors[0] = base[0].darken(0.4);
+        colors[1..7].copy_from_slice(&base[1..7]);
+        colors[7] = base[7].lighten(0.6);
+        colors[8] = colors[0].lighten(0.25);
+        for i in 9..15 {
+            colors[i] = colors[i - 8].lighten(0.15);
+        }
+        colors[15] = base[7].lighten(0.8);
+
+        Ok(Self {
+            colors,
+            background: colors[0],
+            foreground: colors[15],
+            cursor: colors[15],
+        })
+    }
+
+    /// Gets a color by the name used in the templates
+    /// (`color0`..`color15`, `background`, `foreground` or `cursor`).
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<Color> {
+        match name {
+            "background" => Some(self.background),
+            "foreground" => Some(self.foreground),
+            "cursor" => Some(self.cursor),
+            _ => name
+                .strip_prefix("color")
+                .and_then(|index| index.parse::<usize>().ok())
+                .and_then(|index| self.colors.get(index).copied()),
+        }
+    }
+}
+
+/// Reduces the given pixels to at most `count` colors.
+fn median_cut(pixels: Vec<Color>, count: usize) -> Vec<Color> {
+    let mut buckets = vec![pixels];
+
+    while buckets.len() < count {
+        // Split the bucket with the widest channel range.
+        let Some((index, channel)) = buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, bucket)| bucket.len() > 1)
+            .map(|(i, bucket)| {
+                let (channel, range) = widest_channel(bucket);
+                (i, channel, range)
+            })
+            .max_by_key(|(_, _, range)| *range)
+            .map(|(i, channel, _)| (i, channel))
+        else {
+            break;
+        };
+
+        let mut bucket = buckets.swap_remove(index);
+        bucket.sort_by_key(|c| channel_value(*c, channel));
+        let upper = bucket.split_off(bucket.len() / 2);
+        buckets.push(bucket);
+        buckets.push(upper);
+    }
+
+    buckets.iter().map(|bucket| average(bucket)).collect()
+}
+
+const fn channel_value(color: Color, channel: usize) -> u8 {
+    match channel {
+        0 => color.r,
+        1 => color.g,
+        _ => color.b,
+    }
+}
+
+/// Returns the channel with the biggest range in the bucket and that range.
+fn widest_channel(bucket: &[Color]) -> (usize, u8) {
+    (0..3)
+        .map(|channel| {
+            let values = bucket.iter().map(|c| channel_value(*c, channel));
+            let min = values.clone().min().unwrap_or(0);
+            let max = values.max().unwrap_or(0);
+            (channel, max - min)
+        })
+        .max_by_key(|(_, range)| *range)
+        .unwrap_or((0, 0))
+}
+
+fn average(bucket: &[Color]) -> Color {
+    let len = bucket.len().max(1) as u64;
+    let sum = |channel| {
+        bucket
+            .iter()
+            .map(|c| u64::from(channel_value(*c, channel)))
+            .sum::<u64>()
+    };
+    Color::new(
+        (sum(0) / len) as u8,
+        (sum(1) / len) as u8,
+        (sum(2) / len) as u8,
+    )
+}
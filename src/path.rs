@@ -6,7 +6,7 @@ use std::{
 
 use anyhow::{anyhow, Result};
 
-use crate::configuration::Settings;
+use crate::{configuration::Settings, metadata::get_metadata};
 
 /// A wrapper for a path that can be either a file or a folder.
 pub enum File {
@@ -114,17 +114,17 @@ impl ImagePath {
     ///  folder1 is an animated wallpaper
     ///  wallpaper1 is an animated wallpaper
     ///  wallpaper2 is not an animated wallpaper
+    ///
+    /// The frames are ordered by the number ending their name, e.g.
+    /// `frame12.png`, a file of folder1 without one is not a frame.
     pub fn is_animated(&mut self, settings: &Settings) -> bool {
-        if self.animated_info_cached {
-            return self.animated_info.is_some();
-        }
-
-        let is_animated = self.check_if_animated(settings);
-        if is_animated {
-            self.update_animated_info();
+        if !self.animated_info_cached {
+            if self.check_if_animated(settings) {
+                self.update_animated_info();
+            }
+            self.animated_info_cached = true;
         }
-        self.animated_info_cached = true;
-        is_animated
+        self.animated_info.is_some()
     }
 
     /// Helper function for `is_animated`.
@@ -147,7 +147,7 @@ impl ImagePath {
     }
 
     fn update_animated_info(&mut self) {
-        self.animated_info = Some(AnimatedInfo::new(&self.path));
+        self.animated_info = AnimatedInfo::new(&self.path);
     }
 
     /// Gets the name of the folder that contains the given path.
@@ -174,7 +174,14 @@ impl ImagePath {
         &self.path
     }
 
+    /// Gets the number of seconds the wallpaper must be displayed: the
+    /// duration set in its metadata or `sleep_time`. For animated wallpapers
+    /// this time is split between all the frames.
     pub fn get_sleep_time(&mut self, settings: &Settings) -> Result<u64> {
+        let sleep_time = get_metadata(settings, &self.path)
+            .duration
+            .unwrap_or(settings.sleep_time);
+
         if self.is_animated(settings) {
             let parent_path = self.path.parent().ok_or(anyhow!(
                 "failed to get parent directory of the animated walpaper"
@@ -188,9 +195,9 @@ impl ImagePath {
                 })?
                 .count();
 
-            Ok(settings.sleep_time / number_of_wallpapers as u64)
+            Ok(sleep_time / number_of_wallpapers as u64)
         } else {
-            Ok(settings.sleep_time)
+            Ok(sleep_time)
         }
     }
 }
@@ -207,6 +214,21 @@ impl From<String> for ImagePath {
     }
 }
 
+/// Checks if a wallpaper is a frame of an animated wallpaper other than the
+/// first one, for the updates that are only worth doing once per animation.
+#[must_use]
+pub fn is_later_frame(settings: &Settings, wallpaper: &str) -> bool {
+    ImagePath::new(wallpaper.into())
+        .and_then(|mut image| {
+            if image.is_animated(settings) {
+                image.get_animated_number()
+            } else {
+                None
+            }
+        })
+        .is_some_and(|number| number > 1)
+}
+
 /// A wrapper for a path that is a folder.
 pub struct AnimtaedFolder {
     path: PathBuf,
@@ -243,14 +265,14 @@ struct AnimatedInfo {
 }
 
 impl AnimatedInfo {
-    fn new(path: &Path) -> Self {
+    fn new(path: &Path) -> Option<Self> {
         let animated_folder = Self::update_animated_folder(path);
-        let animated_number = Self::update_animated_number(path);
+        let animated_number = Self::update_animated_number(path)?;
 
-        Self {
+        Some(Self {
             animated_folder,
             animated_number,
-        }
+        })
     }
 
     fn update_animated_folder(path: &Path) -> String {
@@ -263,11 +285,10 @@ impl AnimatedInfo {
             .to_owned()
     }
 
-    fn update_animated_number(path: &Path) -> u32 {
-        path.file_stem()
-            .expect("failed to get file name")
-            .to_str()
-            .expect("failed to convert file name to str")
+    /// Gets the number ending the name of a frame, if it has one.
+    fn update_animated_number(path: &Path) -> Option<u32> {
+        path.file_stem()?
+            .to_str()?
             .chars()
             .rev()
             .take_while(char::is_ascii_digit)
@@ -276,6 +297,6 @@ impl AnimatedInfo {
             .rev()
             .collect::<String>()
             .parse::<u32>()
-            .expect("failed to parse animated number")
+            .ok()
     }
 }
@@ -0,0 +1,263 @@
+use std::{
+    fs,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use image::{
+    codecs::jpeg::JpegEncoder, imageops, imageops::FilterType, DynamicImage, ImageFormat, RgbImage,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{atomic, configuration::Settings, process, setter::Fit};
+
+/// Resolution detected with `xrandr`, kept as it is needed for every change.
+static DETECTED_RESOLUTION: Mutex<Option<Resolution>> = Mutex::new(None);
+
+/// Quality used when encoding the scaled wallpapers.
+const JPEG_QUALITY: u8 = 95;
+
+/// Resolution of the output the wallpaper is displayed on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resolution {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl TryFrom<&str> for Resolution {
+    type Error = anyhow::Error;
+
+    /// Parses a resolution written as `<width>x<height>`, e.g. `1920x1080`.
+    fn try_from(value: &str) -> Result<Self> {
+        let (width, height) = value.split_once('x').ok_or(anyhow!(
+            "invalid resolution `{value}`, expected <width>x<height>"
+        ))?;
+        let parse = |n: &str| {
+            n.trim()
+                .parse::<u32>()
+                .ok()
+                .filter(|n| *n > 0)
+                .ok_or(anyhow!("invalid resolution `{value}`"))
+        };
+
+        Ok(Self {
+            width: parse(width)?,
+            height: parse(height)?,
+        })
+    }
+}
+
+/// Gets the resolution wallpapers are scaled to: the configured one or, if
+/// there is none, the current size of the X screen as reported by `xrandr`.
+pub fn get_resolution(settings: &Settings) -> Result<Resolution> {
+    if let Some(resolution) = &settings.resolution {
+        return Resolution::try_from(resolution.as_str());
+    }
+
+    let mut detected = DETECTED_RESOLUTION
+        .lock()
+        .unwrap_or_else(|err| err.into_inner());
+    if let Some(resolution) = *detected {
+        return Ok(resolution);
+    }
+
+    let stdout = process::output(
+        Command::new("xrandr").arg("--current"),
+        Duration::from_secs(settings.command_timeout),
+    )?;
+
+    // Screen 0: minimum 8 x 8, current 1920 x 1080, maximum 32767 x 32767
+    let resolution = stdout
+        .lines()
+        .find_map(|line| {
+            let current = line.split(", ").find_map(|s| s.strip_prefix("current "))?;
+            Resolution::try_from(current.replace(' ', "").as_str()).ok()
+        })
+        .ok_or(anyhow!("failed to detect the screen resolution"))?;
+    *detected = Some(resolution);
+    Ok(resolution)
+}
+
+/// Forgets the resolution detected with `xrandr`, so that it is detected
+/// again, e.g. after a screen has been plugged.
+pub fn forget_resolution() {
+    *DETECTED_RESOLUTION
+        .lock()
+        .unwrap_or_else(|err| err.into_inner()) = None;
+}
+
+/// Returns the cache directory of wallshift, where scaled wallpapers are
+/// stored.
+pub fn get_cache_dir() -> Result<PathBuf> {
+    Ok(dirs::cache_dir()
+        .ok_or(anyhow!("failed to get cache directory"))?
+        .join("wallshift"))
+}
+
+/// Renders the wallpaper at the screen resolution with the given fit mode and
+/// returns the path of the result, which is the one that should be given to
+/// the setter (as it already has the size of the screen, using `fill`).
+///
+/// Results are stored in the cache directory under the hash of the contents
+/// of the original image, the target resolution and the fit mode, so every
+/// image is only processed once, whatever its name is.
+pub fn prepare_wallpaper(settings: &Settings, path: &str, fit: Fit) -> Result<String> {
+    let resolution = get_resolution(settings)?;
+    let contents = fs::read(path)?;
+
+    let mut hasher = Sha256::new();
+    hasher.update(&contents);
+    hasher.update(format!(
+        "{}x{}:{}",
+        resolution.width,
+        resolution.height,
+        fit.name()
+    ));
+    let hash = hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+
+    let cache_dir = get_cache_dir()?;
+    let cached = cache_dir.join(format!("{hash}.jpg"));
+    if cached.exists() {
+        return path_to_string(&cached);
+    }
+
+    fs::create_dir_all(&cache_dir)?;
+    let image = image::load_from_memory(&contents)
+        .map_err(|err| anyhow!("failed to decode {path}: {err}"))?;
+    let scaled = render(&image, resolution, fit);
+
+    // An interrupted write is never used.
+    atomic::replace(&cached, |tmp| {
+        let writer = BufWriter::new(fs::File::create(tmp)?);
+        scaled.write_with_encoder(JpegEncoder::new_with_quality(writer, JPEG_QUALITY))?;
+        Ok(())
+    })?;
+
+    path_to_string(&cached)
+}
+
+/// Renders the wallpaper for the lock screen: scaled to the screen
+/// resolution, blurred with a radius of `blur` and darkened by `dim` (from 0
+/// to 1), and writes it as a PNG image to `output`.
+pub fn prepare_lockscreen(
+    settings: &Settings,
+    path: &str,
+    blur: f32,
+    dim: f32,
+    output: &Path,
+) -> Result<()> {
+    let resolution = get_resolution(settings)?;
+    let image = image::open(path).map_err(|err| anyhow!("failed to decode {path}: {err}"))?;
+
+    let mut image = DynamicImage::ImageRgb8(render(&image, resolution, Fit::Fill));
+    if blur > 0.0 {
+        image = image.fast_blur(blur);
+    }
+    let mut image = image.to_rgb8();
+    if dim > 0.0 {
+        let factor = 1.0 - dim.clamp(0.0, 1.0);
+        for channel in image.iter_mut() {
+            *channel = (f32::from(*channel) * factor) as u8;
+        }
+    }
+
+    write_image(&DynamicImage::ImageRgb8(image), ImageFormat::Png, output)
+}
+
+/// Converts the wallpaper to the given format, scaled and cropped to `size`
+/// if one is given, and writes it to `output`.
+pub fn export_image(
+    path: &str,
+    format: ImageFormat,
+    size: Option<Resolution>,
+    output: &Path,
+) -> Result<()> {
+    let image = image::open(path).map_err(|err| anyhow!("failed to decode {path}: {err}"))?;
+    let image = match size {
+        Some(size) => DynamicImage::ImageRgb8(render(&image, size, Fit::Fill)),
+        // JPEG has no transparency.
+        None if format == ImageFormat::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8()),
+        None => image,
+    };
+    write_image(&image, format, output)
+}
+
+/// Writes an image under a temporary name and moves it to `output`, so
+/// programs reading `output` never see half an image.
+fn write_image(image: &DynamicImage, format: ImageFormat, output: &Path) -> Result<()> {
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    atomic::replace(output, |tmp| Ok(image.save_with_format(tmp, format)?))
+}
+
+/// Places the image on a canvas of the given resolution.
+fn render(image: &DynamicImage, resolution: Resolution, fit: Fit) -> RgbImage {
+    let Resolution { width, height } = resolution;
+
+    match fit {
+        Fit::Fill => image
+            .resize_to_fill(width, height, FilterType::Lanczos3)
+            .to_rgb8(),
+        Fit::Stretch => image
+            .resize_exact(width, height, FilterType::Lanczos3)
+            .to_rgb8(),
+        Fit::Fit => {
+            let mut canvas = RgbImage::new(width, height);
+            overlay_centered(
+                &mut canvas,
+                &image.resize(width, height, FilterType::Lanczos3).to_rgb8(),
+            );
+            canvas
+        }
+        Fit::Center => {
+            let mut canvas = RgbImage::new(width, height);
+            overlay_centered(&mut canvas, &image.to_rgb8());
+            canvas
+        }
+        Fit::Tile => {
+            let tile = image.to_rgb8();
+            let mut canvas = RgbImage::new(width, height);
+            for y in (0..height).step_by(tile.height().max(1) as usize) {
+                for x in (0..width).step_by(tile.width().max(1) as usize) {
+                    imageops::replace(&mut canvas, &tile, i64::from(x), i64::from(y));
+                }
+            }
+            canvas
+        }
+        Fit::FitWithBlurredBackdrop => {
+            // Blurring a downscaled copy is much cheaper and looks the same.
+            let mut canvas = image
+                .resize_to_fill(width / 8 + 1, height / 8 + 1, FilterType::Triangle)
+                .blur(4.0)
+                .resize_exact(width, height, FilterType::Triangle)
+                .to_rgb8();
+            overlay_centered(
+                &mut canvas,
+                &image.resize(width, height, FilterType::Lanczos3).to_rgb8(),
+            );
+            canvas
+        }
+    }
+}
+
+fn overlay_centered(canvas: &mut RgbImage, image: &RgbImage) {
+    let x = (i64::from(canvas.width()) - i64::from(image.width())) / 2;
+    let y = (i64::from(canvas.height()) - i64::from(image.height())) / 2;
+    imageops::replace(canvas, image, x, y);
+}
+
+fn path_to_string(path: &Path) -> Result<String> {
+    Ok(path
+        .to_str()
+        .ok_or(anyhow!("failed to convert cache path to str"))?
+        .to_owned())
+}
@@ -0,0 +1,104 @@
+use std::{
+    io::Read,
+    process::{Command, Stdio},
+    sync::{
+        mpsc::{self, Receiver},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+
+/// Time between two checks of the state of a running command.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Runs an external command, waiting at most `timeout` for it to finish.
+///
+/// The command fails if it cannot be started, exits with a non-zero status
+/// or is still running after the timeout, in which case it is killed. The
+/// error contains what the command wrote to stderr.
+pub fn run(command: &mut Command, timeout: Duration) -> Result<()> {
+    execute(command.stdout(Stdio::null()), timeout).map(drop)
+}
+
+/// Runs an external command like `run`, and returns what it wrote to stdout.
+pub fn output(command: &mut Command, timeout: Duration) -> Result<String> {
+    execute(command.stdout(Stdio::piped()), timeout)
+}
+
+fn execute(command: &mut Command, timeout: Duration) -> Result<String> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let mut child = command
+        .stdin(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| anyhow!("failed to run `{program}`: {err}"))?;
+
+    // Read on the side, so the command does not block on a full pipe.
+    let stdout = PipeReader::spawn(child.stdout.take());
+    let stderr = PipeReader::spawn(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(anyhow!(
+                "`{program}` did not finish after {} seconds",
+                timeout.as_secs()
+            ));
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    if status.success() {
+        return Ok(stdout.finish(deadline));
+    }
+    match stderr.finish(deadline).trim() {
+        "" => Err(anyhow!("`{program}` failed with {status}")),
+        stderr => Err(anyhow!("`{program}` failed with {status}: {stderr}")),
+    }
+}
+
+/// A pipe of a command, read in a thread.
+struct PipeReader {
+    output: Arc<Mutex<Vec<u8>>>,
+    closed: Receiver<()>,
+}
+
+impl PipeReader {
+    /// Starts reading the pipe, if there is one.
+    fn spawn(pipe: Option<impl Read + Send + 'static>) -> Self {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let (sender, closed) = mpsc::channel();
+        let read = Arc::clone(&output);
+        thread::spawn(move || {
+            let mut buffer = [0; 4096];
+            if let Some(mut pipe) = pipe {
+                while let Ok(length @ 1..) = pipe.read(&mut buffer) {
+                    read.lock()
+                        .unwrap_or_else(|err| err.into_inner())
+                        .extend_from_slice(&buffer[..length]);
+                }
+            }
+            let _ = sender.send(());
+        });
+        Self { output, closed }
+    }
+
+    /// Waits for the pipe to be closed and returns what was written to it.
+    /// A process forked by the command can keep the pipe open after it
+    /// exits, so it is not waited for after the deadline.
+    fn finish(self, deadline: Instant) -> String {
+        let _ = self
+            .closed
+            .recv_timeout(deadline.saturating_duration_since(Instant::now()));
+        let output = self.output.lock().unwrap_or_else(|err| err.into_inner());
+        String::from_utf8_lossy(&output).into_owned()
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    env,
+    fs::{self, DirBuilder, File, OpenOptions},
+    io::ErrorKind,
+    os::unix::{
+        fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+        net::UnixStream,
+    },
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+
+/// Gets an identifier of the graphical session wallshift is running in,
+/// based on `$WAYLAND_DISPLAY` or `$DISPLAY`. Each session has its own
+/// daemon.
+#[must_use]
+pub fn get_session_id() -> String {
+    let sanitize = |s: &str| {
+        s.chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect::<String>()
+    };
+
+    if let Some(display) = env::var("WAYLAND_DISPLAY").ok().filter(|d| !d.is_empty()) {
+        // Usually already `wayland-<n>`, but it can be an absolute path.
+        let name = Path::new(&display)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or(&display)
+            .to_owned();
+        return sanitize(&name);
+    }
+    if let Some(display) = env::var("DISPLAY").ok().filter(|d| !d.is_empty()) {
+        return format!("x11-{}", sanitize(display.trim_start_matches(':')));
+    }
+    "default".to_owned()
+}
+
+/// Returns the directory holding the runtime files (pid file, output of the
+/// daemon) of the current user, creating it if needed.
+///
+/// It is `$XDG_RUNTIME_DIR/wallshift` or, if there is no runtime directory,
+/// `/tmp/wallshift-<uid>`. Only the user can access it.
+pub fn get_runtime_dir() -> Result<PathBuf> {
+    // SAFETY: getuid cannot fail.
+    let uid = unsafe { libc::getuid() };
+    let path = dirs::runtime_dir().map_or_else(
+        || env::temp_dir().join(format!("wallshift-{uid}")),
+        |dir| dir.join("wallshift"),
+    );
+
+    // Not recursive: the directory must be created by this call, or checked
+    // if it already exists, someone else could have created it in a shared
+    // directory like /tmp.
+    match DirBuilder::new().mode(0o700).create(&path) {
+        Ok(()) => (),
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => (),
+        Err(err) => return Err(anyhow!("failed to create {}: {err}", path.display())),
+    }
+
+    let metadata = fs::symlink_metadata(&path)?;
+    if !metadata.is_dir() || metadata.uid() != uid {
+        return Err(anyhow!(
+            "{} is not a directory owned by the current user",
+            path.display()
+        ));
+    }
+    if metadata.permissions().mode() & 0o077 != 0 {
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o700))?;
+    }
+
+    Ok(path)
+}
+
+/// Returns the path of a runtime file of the current session, e.g.
+/// `$XDG_RUNTIME_DIR/wallshift/x11-0.pid` for the `pid` extension.
+pub fn get_runtime_path(extension: &str) -> Result<PathBuf> {
+    get_session_runtime_path(&get_session_id(), extension)
+}
+
+/// Returns the path of a runtime file of the given session.
+pub fn get_session_runtime_path(session: &str, extension: &str) -> Result<PathBuf> {
+    Ok(get_runtime_dir()?.join(format!("{session}.{extension}")))
+}
+
+/// Checks if the daemon of the given session is running: its control socket
+/// accepts connections, or the process of its pid file exists. Only daemons
+/// started in the background have a pid file, `--foreground` ones do not.
+#[must_use]
+pub fn is_daemon_running(session: &str) -> bool {
+    let listening = get_session_runtime_path(session, "sock")
+        .is_ok_and(|path| UnixStream::connect(path).is_ok());
+
+    listening
+        || get_session_runtime_path(session, "pid")
+            .ok()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|pid| pid.trim().parse::<libc::pid_t>().ok())
+            // SAFETY: signal 0 only checks that the process exists.
+            .is_some_and(|pid| unsafe { libc::kill(pid, 0) } == 0)
+}
+
+/// Opens a runtime file for appending, only readable by the user.
+pub fn open_runtime_file(path: &Path) -> Result<File> {
+    Ok(OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)?)
+}
@@ -0,0 +1,530 @@
+use std::{
+    collections::BTreeSet,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local, TimeDelta, Utc};
+use cron::Schedule;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::configuration::Settings;
+
+/// Maximum time the daemon waits for an event, so that a suspension of the
+/// system is noticed soon after the wake up.
+const WAKE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Difference between the wall clock and the monotonic clock above which the
+/// system is considered to have been suspended.
+const WAKE_THRESHOLD: TimeDelta = TimeDelta::seconds(30);
+
+/// What the daemon does when it notices that the system has been suspended.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnWake {
+    /// Changes the wallpaper right away.
+    Change,
+    /// Keeps the planned time of the next change, the wallpaper changes right
+    /// away only if it has passed.
+    #[default]
+    Keep,
+    /// Waits for a whole interval from the wake up.
+    Restart,
+}
+
+/// Source of time of the daemon, so the scheduling can be driven by
+/// something else than the system clock.
+pub trait Clock {
+    /// Current time of the wall clock.
+    fn now(&self) -> DateTime<Utc>;
+    /// Current time of a monotonic clock that does not advance while the
+    /// system is suspended.
+    fn instant(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Timer of the next change of the daemon. It is frozen while the slideshow
+/// is paused, and notices the suspensions of the system by comparing the
+/// wall clock with the monotonic clock.
+pub struct Timer {
+    clock: Box<dyn Clock>,
+    /// Time of the next change.
+    deadline: DateTime<Utc>,
+    /// Length of the current interval, used to restart it.
+    interval: TimeDelta,
+    /// When the current pause started, if paused.
+    paused_since: Option<DateTime<Utc>>,
+    /// Last time the clocks were read, to detect suspensions.
+    last_check: (DateTime<Utc>, Instant),
+}
+
+impl Timer {
+    /// Creates a timer that is already due.
+    #[must_use]
+    pub fn new(clock: Box<dyn Clock>) -> Self {
+        let now = clock.now();
+        let last_check = (now, clock.instant());
+        Self {
+            clock,
+            deadline: now,
+            interval: TimeDelta::zero(),
+            paused_since: None,
+            last_check,
+        }
+    }
+
+    #[must_use]
+    pub fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
+    }
+
+    #[must_use]
+    pub const fn deadline(&self) -> DateTime<Utc> {
+        self.deadline
+    }
+
+    /// Starts a new interval, ending at `deadline`. While paused, the
+    /// interval starts once the slideshow is resumed.
+    pub fn schedule(&mut self, deadline: DateTime<Utc>) {
+        let now = self.clock.now();
+        self.interval = (deadline - now).max(TimeDelta::zero());
+        self.deadline = deadline;
+        if self.paused_since.is_some() {
+            self.paused_since = Some(now);
+        }
+    }
+
+    /// Continues an interval of the given length, ending at `deadline`, e.g.
+    /// the one planned by a previous run of the daemon.
+    pub fn restore(&mut self, deadline: DateTime<Utc>, interval: TimeDelta) {
+        self.deadline = deadline;
+        self.interval = interval;
+    }
+
+    pub fn pause(&mut self) {
+        self.pause_since(self.clock.now());
+    }
+
+    /// Pauses the timer from a given time, e.g. when the pause started
+    /// before the daemon.
+    pub fn pause_since(&mut self, since: DateTime<Utc>) {
+        if self.paused_since.is_none() {
+            self.paused_since = Some(since);
+        }
+    }
+
+    /// Ends the pause, the time spent paused is added to the current
+    /// interval. Returns `false` if the timer was not paused.
+    pub fn resume(&mut self) -> bool {
+        let Some(paused_since) = self.paused_since.take() else {
+            return false;
+        };
+        self.deadline += self.clock.now() - paused_since;
+        true
+    }
+
+    /// Checks if the system has been suspended since the last check, and
+    /// applies `on_wake` if so. Returns how long it was suspended.
+    pub fn check_wake(&mut self, on_wake: OnWake) -> Option<TimeDelta> {
+        let now = (self.clock.now(), self.clock.instant());
+        let wall_elapsed = now.0 - self.last_check.0;
+        let monotonic_elapsed = TimeDelta::from_std(now.1 - self.last_check.1).unwrap_or_default();
+        self.last_check = now;
+
+        let suspended = wall_elapsed - monotonic_elapsed;
+        if suspended <= WAKE_THRESHOLD {
+            return None;
+        }
+        match on_wake {
+            OnWake::Change => self.deadline = now.0,
+            OnWake::Keep => (),
+            OnWake::Restart => self.deadline = now.0 + self.interval,
+        }
+        Some(suspended)
+    }
+
+    /// Checks if the wallpaper has to be changed. Nothing changes while
+    /// paused, not even the animations.
+    #[must_use]
+    pub fn is_due(&self) -> bool {
+        self.paused_since.is_none() && self.clock.now() >= self.deadline
+    }
+
+    /// How long to wait before the timer has to be checked again, `pause_end`
+    /// being the end of the current pause, if it has one.
+    #[must_use]
+    pub fn get_timeout(&self, pause_end: Option<DateTime<Utc>>) -> Duration {
+        let next = if self.paused_since.is_some() {
+            pause_end
+        } else {
+            Some(self.deadline)
+        };
+
+        next.map_or(WAKE_CHECK_INTERVAL, |next| {
+            (next - self.clock.now())
+                .to_std()
+                .unwrap_or_default()
+                .min(WAKE_CHECK_INTERVAL)
+        })
+    }
+}
+
+/// Computes when the wallpaper displayed now, for `sleep_time` seconds,
+/// must be changed.
+///
+/// - If a `cron` expression is configured, at its next occurrence.
+/// - If `align` is enabled, at the next multiple of `sleep_time` since the
+///   local midnight, e.g. every hour on the hour for a one hour interval.
+/// - Otherwise, `sleep_time` seconds from now.
+///
+/// Frames of animated wallpapers always follow their own interval.
+pub fn compute_next_change(
+    settings: &Settings,
+    now: DateTime<Utc>,
+    sleep_time: u64,
+    animated: bool,
+) -> Result<DateTime<Utc>> {
+    let interval = TimeDelta::seconds(i64::try_from(sleep_time.max(1))?);
+
+    if animated {
+        return Ok(now + interval);
+    }
+
+    if let Some(expression) = &settings.cron {
+        return parse_cron(expression)?
+            .after(&now.with_timezone(&Local))
+            .next()
+            .map(|next| next.with_timezone(&Utc))
+            .ok_or(anyhow!(
+                "the cron expression `{expression}` has no next occurrence"
+            ));
+    }
+
+    if settings.align {
+        let offset = i64::from(now.with_timezone(&Local).offset().local_minus_utc());
+        let local = now.timestamp() + offset;
+        let interval = interval.num_seconds();
+        let next = (local / interval + 1) * interval - offset;
+        return DateTime::from_timestamp(next, 0).ok_or(anyhow!("invalid next change time"));
+    }
+
+    Ok(now + interval)
+}
+
+/// Parses a cron expression. The usual 5 fields format (minute, hour, day
+/// of the month, month, day of the week) is accepted, as well as the 6 and 7
+/// fields formats with seconds and years.
+///
+/// In the 5 fields format, the days of the week are numbered like crontab
+/// does, from 0 or 7 for Sunday to 6 for Saturday. The 6 and 7 fields
+/// formats number them from 1 for Sunday to 7 for Saturday.
+pub fn parse_cron(expression: &str) -> Result<Schedule> {
+    let fields = expression.split_whitespace().collect::<Vec<_>>();
+    let converted = if let [minute, hour, day, month, day_of_week] = fields[..] {
+        format!(
+            "0 {minute} {hour} {day} {month} {}",
+            convert_days_of_week(day_of_week).ok_or(anyhow!(
+                "invalid cron expression `{expression}`: invalid day of the week"
+            ))?
+        )
+    } else {
+        expression.to_owned()
+    };
+
+    Schedule::from_str(&converted)
+        .map_err(|err| anyhow!("invalid cron expression `{expression}`: {err}"))
+}
+
+/// Converts a crontab day of the week field (0 or 7 for Sunday) to the
+/// numbering of the cron crate (1 for Sunday). Ranges and steps are expanded
+/// to lists, since a range ending on Sunday wraps around. Names are kept.
+fn convert_days_of_week(field: &str) -> Option<String> {
+    if field == "*" || field == "?" {
+        return Some(field.to_owned());
+    }
+
+    let mut names = Vec::new();
+    let mut days = BTreeSet::new();
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, Some(step.parse::<u32>().ok()?)),
+            None => (item, None),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (0, 6),
+            Some((start, end)) => match (start.parse::<u32>(), end.parse::<u32>()) {
+                (Ok(start), Ok(end)) => (start, end),
+                _ => {
+                    names.push(item);
+                    continue;
+                }
+            },
+            None => match range.parse::<u32>() {
+                Ok(day) if step.is_some() => (day, 6),
+                Ok(day) => (day, day),
+                Err(_) => {
+                    names.push(item);
+                    continue;
+                }
+            },
+        };
+        if start > end || end > 7 || step == Some(0) {
+            return None;
+        }
+        let step = usize::try_from(step.unwrap_or(1)).ok()?;
+        days.extend((start..=end).step_by(step).map(|day| day % 7 + 1));
+    }
+
+    Some(
+        names
+            .into_iter()
+            .map(str::to_owned)
+            .chain(days.iter().map(u32::to_string))
+            .collect::<Vec<_>>()
+            .join(","),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use chrono::{Datelike, NaiveDate, Weekday};
+
+    use super::*;
+
+    /// A clock that only moves when told to.
+    #[derive(Clone)]
+    struct FakeClock(Rc<Cell<(DateTime<Utc>, Instant)>>);
+
+    impl FakeClock {
+        fn new() -> Self {
+            Self(Rc::new(Cell::new((Utc::now(), Instant::now()))))
+        }
+
+        /// Lets time pass while the system is running.
+        fn advance(&self, seconds: i64) {
+            let (now, instant) = self.0.get();
+            self.0.set((
+                now + TimeDelta::seconds(seconds),
+                instant + Duration::from_secs(seconds.unsigned_abs()),
+            ));
+        }
+
+        /// Lets time pass while the system is suspended: the monotonic clock
+        /// does not move.
+        fn suspend(&self, seconds: i64) {
+            let (now, instant) = self.0.get();
+            self.0.set((now + TimeDelta::seconds(seconds), instant));
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> DateTime<Utc> {
+            self.0.get().0
+        }
+
+        fn instant(&self) -> Instant {
+            self.0.get().1
+        }
+    }
+
+    /// A timer with a change planned in 10 minutes.
+    fn scheduled_timer() -> (FakeClock, Timer) {
+        let clock = FakeClock::new();
+        let mut timer = Timer::new(Box::new(clock.clone()));
+        timer.schedule(clock.now() + TimeDelta::minutes(10));
+        (clock, timer)
+    }
+
+    #[test]
+    fn wake_detection() {
+        let (clock, mut timer) = scheduled_timer();
+
+        clock.advance(120);
+        assert_eq!(timer.check_wake(OnWake::Keep), None);
+        // Small differences between the clocks are not suspensions.
+        clock.suspend(20);
+        assert_eq!(timer.check_wake(OnWake::Keep), None);
+
+        clock.advance(5);
+        clock.suspend(3600);
+        assert_eq!(
+            timer.check_wake(OnWake::Keep),
+            Some(TimeDelta::seconds(3600))
+        );
+        // Only reported once.
+        assert_eq!(timer.check_wake(OnWake::Keep), None);
+    }
+
+    #[test]
+    fn on_wake_change() {
+        let (clock, mut timer) = scheduled_timer();
+
+        clock.suspend(60);
+        assert!(!timer.is_due());
+        timer.check_wake(OnWake::Change);
+        assert_eq!(timer.deadline(), clock.now());
+        assert!(timer.is_due());
+    }
+
+    #[test]
+    fn on_wake_keep() {
+        let (clock, mut timer) = scheduled_timer();
+        let deadline = timer.deadline();
+
+        clock.suspend(60);
+        timer.check_wake(OnWake::Keep);
+        assert_eq!(timer.deadline(), deadline);
+        assert!(!timer.is_due());
+
+        clock.suspend(3600);
+        timer.check_wake(OnWake::Keep);
+        assert_eq!(timer.deadline(), deadline);
+        assert!(timer.is_due());
+    }
+
+    #[test]
+    fn on_wake_restart() {
+        let (clock, mut timer) = scheduled_timer();
+
+        clock.advance(300);
+        clock.suspend(3600);
+        timer.check_wake(OnWake::Restart);
+        assert_eq!(timer.deadline(), clock.now() + TimeDelta::minutes(10));
+    }
+
+    #[test]
+    fn restart_after_resume_waits_a_whole_interval() {
+        let (clock, mut timer) = scheduled_timer();
+
+        clock.advance(100);
+        timer.pause();
+        clock.advance(200);
+        assert!(timer.resume());
+        clock.suspend(3600);
+        timer.check_wake(OnWake::Restart);
+        assert_eq!(timer.deadline(), clock.now() + TimeDelta::minutes(10));
+    }
+
+    #[test]
+    fn restart_after_restore_waits_a_whole_interval() {
+        let clock = FakeClock::new();
+        let mut timer = Timer::new(Box::new(clock.clone()));
+        timer.restore(clock.now() + TimeDelta::minutes(2), TimeDelta::minutes(10));
+
+        clock.suspend(3600);
+        timer.check_wake(OnWake::Restart);
+        assert_eq!(timer.deadline(), clock.now() + TimeDelta::minutes(10));
+    }
+
+    #[test]
+    fn resume_after_pause() {
+        let (clock, mut timer) = scheduled_timer();
+        let deadline = timer.deadline();
+
+        clock.advance(100);
+        timer.pause();
+        clock.advance(1000);
+        assert!(!timer.is_due());
+        assert!(timer.resume());
+        assert_eq!(timer.deadline(), deadline + TimeDelta::seconds(1000));
+
+        // The 500 seconds left before the pause.
+        clock.advance(499);
+        assert!(!timer.is_due());
+        clock.advance(1);
+        assert!(timer.is_due());
+
+        assert!(!timer.resume());
+    }
+
+    #[test]
+    fn schedule_while_paused() {
+        let clock = FakeClock::new();
+        let mut timer = Timer::new(Box::new(clock.clone()));
+
+        timer.pause();
+        clock.advance(100);
+        let start = clock.now();
+        timer.schedule(start + TimeDelta::minutes(10));
+        clock.advance(50);
+        timer.resume();
+        // The interval starts once resumed.
+        assert_eq!(
+            timer.deadline(),
+            start + TimeDelta::minutes(10) + TimeDelta::seconds(50)
+        );
+    }
+
+    #[test]
+    fn timeout() {
+        let (clock, mut timer) = scheduled_timer();
+        assert_eq!(timer.get_timeout(None), WAKE_CHECK_INTERVAL);
+
+        clock.advance(595);
+        assert_eq!(timer.get_timeout(None), Duration::from_secs(5));
+        clock.advance(10);
+        assert_eq!(timer.get_timeout(None), Duration::ZERO);
+
+        timer.pause();
+        assert_eq!(timer.get_timeout(None), WAKE_CHECK_INTERVAL);
+        let pause_end = clock.now() + TimeDelta::seconds(3);
+        assert_eq!(timer.get_timeout(Some(pause_end)), Duration::from_secs(3));
+    }
+
+    fn next_days(expression: &str) -> Vec<Weekday> {
+        // A Saturday.
+        let start = NaiveDate::from_ymd_opt(2024, 6, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap()
+            .and_utc();
+        parse_cron(expression)
+            .unwrap()
+            .after(&start)
+            .take(7)
+            .map(|time| time.weekday())
+            .collect()
+    }
+
+    #[test]
+    fn crontab_days_of_week() {
+        use Weekday::{Fri, Mon, Sat, Sun, Thu, Tue, Wed};
+
+        assert_eq!(
+            next_days("0 9 * * 1-5"),
+            [Mon, Tue, Wed, Thu, Fri, Mon, Tue]
+        );
+        assert_eq!(next_days("0 9 * * 0"), [Sun; 7]);
+        assert_eq!(next_days("0 9 * * 7"), [Sun; 7]);
+        assert_eq!(
+            next_days("0 9 * * 5-7"),
+            [Sun, Fri, Sat, Sun, Fri, Sat, Sun]
+        );
+        assert_eq!(
+            next_days("0 9 * * */2"),
+            [Sun, Tue, Thu, Sat, Sun, Tue, Thu]
+        );
+        assert_eq!(
+            next_days("0 9 * * Mon,3"),
+            [Mon, Wed, Mon, Wed, Mon, Wed, Mon]
+        );
+        // The 6 fields format keeps the numbering of the cron crate.
+        assert_eq!(next_days("0 0 9 * * 1"), [Sun; 7]);
+        assert!(parse_cron("0 9 * * 8").is_err());
+        assert!(parse_cron("0 9 * * 5-2").is_err());
+    }
+}
@@ -0,0 +1,117 @@
+use std::{process::Command, time::Duration};
+
+use anyhow::Result;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::process;
+
+/// How the wallpaper is placed on the screen.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Fit {
+    /// Scaled to cover the whole screen, cropping what does not fit.
+    #[default]
+    Fill,
+    /// Scaled to be entirely visible, with borders around it.
+    Fit,
+    /// Displayed at its original size in the center of the screen.
+    Center,
+    /// Repeated at its original size to cover the screen.
+    Tile,
+    /// Scaled to the screen size without keeping its aspect ratio.
+    Stretch,
+    /// Like `fit`, but the borders are filled with a blurred copy of the image.
+    FitWithBlurredBackdrop,
+}
+
+impl Fit {
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Fill => "fill",
+            Self::Fit => "fit",
+            Self::Center => "center",
+            Self::Tile => "tile",
+            Self::Stretch => "stretch",
+            Self::FitWithBlurredBackdrop => "fit-with-blurred-backdrop",
+        }
+    }
+}
+
+/// A program that sets the wallpaper.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Setter {
+    #[default]
+    Feh,
+    Xwallpaper,
+    Hsetroot,
+    /// The `swww` daemon, for Wayland compositors.
+    Swww,
+}
+
+impl Setter {
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Feh => "feh",
+            Self::Xwallpaper => "xwallpaper",
+            Self::Hsetroot => "hsetroot",
+            Self::Swww => "swww",
+        }
+    }
+
+    /// Translates a fit mode into the options of the setter.
+    /// Returns `None` if the setter cannot display the wallpaper that way,
+    /// in which case wallshift has to render it itself.
+    #[must_use]
+    pub const fn fit_args(self, fit: Fit) -> Option<&'static [&'static str]> {
+        match self {
+            Self::Feh => match fit {
+                Fit::Fill => Some(&["--bg-fill"]),
+                Fit::Fit => Some(&["--bg-max"]),
+                Fit::Center => Some(&["--bg-center"]),
+                Fit::Tile => Some(&["--bg-tile"]),
+                Fit::Stretch => Some(&["--bg-scale"]),
+                Fit::FitWithBlurredBackdrop => None,
+            },
+            Self::Xwallpaper => match fit {
+                Fit::Fill => Some(&["--zoom"]),
+                Fit::Fit => Some(&["--maximize"]),
+                Fit::Center => Some(&["--center"]),
+                Fit::Tile => Some(&["--tile"]),
+                Fit::Stretch => Some(&["--stretch"]),
+                Fit::FitWithBlurredBackdrop => None,
+            },
+            Self::Hsetroot => match fit {
+                Fit::Fill => Some(&["-cover"]),
+                Fit::Fit => Some(&["-full"]),
+                Fit::Center => Some(&["-center"]),
+                Fit::Tile => Some(&["-tile"]),
+                Fit::Stretch => Some(&["-fill"]),
+                Fit::FitWithBlurredBackdrop => None,
+            },
+            Self::Swww => match fit {
+                Fit::Fill => Some(&["img", "--resize", "crop"]),
+                Fit::Fit => Some(&["img", "--resize", "fit"]),
+                Fit::Center => Some(&["img", "--resize", "no"]),
+                Fit::Stretch => Some(&["img", "--resize", "stretch"]),
+                Fit::Tile | Fit::FitWithBlurredBackdrop => None,
+            },
+        }
+    }
+
+    /// Sets the wallpaper, falling back to `fill` if the fit mode
+    /// is not supported by the setter.
+    ///
+    /// Fails if the setter exits with an error or runs for longer than
+    /// `timeout`.
+    pub fn set(self, path: &str, fit: Fit, timeout: Duration) -> Result<()> {
+        let args = self
+            .fit_args(fit)
+            .or_else(|| self.fit_args(Fit::Fill))
+            .unwrap_or_default();
+
+        process::run(Command::new(self.name()).args(args).arg(path), timeout)
+    }
+}
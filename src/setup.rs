@@ -1,86 +1,265 @@
-use std::{fs::File, thread, time::Duration};
+use std::{
+    io::{self, IsTerminal, Write},
+    path::PathBuf,
+};
 
-use daemonize::Daemonize;
+use anyhow::{anyhow, Result};
+use log::{error, info};
 
 use crate::{
     cli::Actions,
     configuration::Settings,
-    data::{set_off, set_on},
-    wallpaper::{get_next_wallpaper, update_wallpaper},
+    control::{send_command, Command},
+    daemon::{run_daemon, run_foreground},
+    data::{
+        clear_queue, clear_tag_filter, discard_wallpaper, get_current_wallpaper, get_queue,
+        is_paused, list_sessions, pause, push_queue, remove_from_queue, remove_session, resume,
+        set_tag_filter,
+    },
+    lockscreen::wait_lockscreen,
+    logging::{init_logger, LogFormat},
+    runtime::{get_session_id, is_daemon_running},
+    wallpaper::{
+        find_wallpapers, get_first_image, peek_next_wallpaper, resolve_wallpaper,
+        restore_wallpaper, update_wallpaper,
+    },
 };
 
+/// Sends a command to the daemon of the session, or runs `fallback` if no
+/// daemon is running.
+fn send_or(command: &Command, fallback: impl FnOnce() -> Result<()>) {
+    match send_command(command) {
+        Ok(true) => (),
+        Ok(false) => {
+            if let Err(err) = fallback() {
+                error!("{err}");
+            }
+        }
+        Err(err) => error!("{err}"),
+    }
+}
+
 fn toggle(settings: &Settings) {
-    match get_next_wallpaper(settings) {
+    match is_paused() {
+        Ok(true) => {
+            info!("skipped toggle: the slideshow is paused");
+            return;
+        }
+        Ok(false) => (),
+        Err(err) => error!("{err}"),
+    }
+
+    match peek_next_wallpaper(settings) {
         Ok(wallpaper) => {
             let path = wallpaper.to_string();
+            info!("changing wallpaper to {path}: toggled");
             if let Err(err) = update_wallpaper(settings, &path) {
-                eprintln!("Error, {err}");
+                error!("{err}");
+                if let Err(err) = discard_wallpaper(&path) {
+                    error!("{err}");
+                }
             }
         }
-        Err(err) => eprintln!("Error, {err}"),
+        Err(err) => error!("{err}"),
+    }
+}
+
+/// Re-applies the wallpaper stored in the state of the session, which is the
+/// current frame for animated wallpapers.
+fn restore(settings: &Settings) -> Result<()> {
+    let wallpaper = get_current_wallpaper()
+        .map_err(|_| anyhow!("there is no wallpaper to restore"))?
+        .to_string();
+    info!("restoring {wallpaper}");
+    restore_wallpaper(settings, &wallpaper)
+}
+
+/// Finds the wallpaper matching a name given by the user. If several match,
+/// the user is asked to pick one when running in a terminal.
+fn pick_wallpaper(settings: &Settings, name: &str) -> Result<PathBuf> {
+    let mut wallpapers = find_wallpapers(settings, name)?;
+    if wallpapers.len() < 2 || !io::stdin().is_terminal() {
+        return resolve_wallpaper(settings, name);
+    }
+
+    for (index, wallpaper) in wallpapers.iter().enumerate() {
+        eprintln!("{} {}", index + 1, wallpaper.display());
+    }
+    eprint!("Pick a wallpaper [1-{}]: ", wallpapers.len());
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    match answer.trim().parse::<usize>() {
+        Ok(position) if (1..=wallpapers.len()).contains(&position) => {
+            Ok(wallpapers.remove(position - 1))
+        }
+        _ => Err(anyhow!("no wallpaper picked")),
+    }
+}
+
+/// Prints the queued wallpapers, numbered from 1.
+fn print_queue() -> Result<()> {
+    for (index, wallpaper) in get_queue()?.iter().enumerate() {
+        println!("{} {wallpaper}", index + 1);
+    }
+    Ok(())
+}
+
+/// Removes a wallpaper from the queue, given by its position or its path.
+fn unqueue(settings: &Settings, wallpaper: &str) -> Result<()> {
+    let removed = match wallpaper.parse::<usize>() {
+        Ok(position) => remove_from_queue(|index, _| index + 1 == position)?,
+        Err(_) => {
+            let path = resolve_wallpaper(settings, wallpaper).map_or_else(
+                |_| wallpaper.to_owned(),
+                |path| path.to_string_lossy().into_owned(),
+            );
+            remove_from_queue(|_, queued| queued == path)?
+        }
+    };
+
+    if removed == 0 {
+        return Err(anyhow!("`{wallpaper}` is not in the queue"));
     }
+    Ok(())
+}
+
+/// Prints the stored sessions, the current one is marked with a `*`.
+fn print_sessions() -> Result<()> {
+    let current = get_session_id();
+    for session in list_sessions()? {
+        println!(
+            "{}{} {} {} {}",
+            if session.id == current { "*" } else { " " },
+            session.id,
+            if is_daemon_running(&session.id) {
+                "running"
+            } else {
+                "stale"
+            },
+            match session.pause.map(|pause| pause.until) {
+                None => "on".to_owned(),
+                Some(None) => "paused".to_owned(),
+                Some(Some(until)) => format!(
+                    "paused until {}",
+                    chrono::DateTime::from_timestamp(until, 0)
+                        .map(|t| t.with_timezone(&chrono::Local).format("%F %R").to_string())
+                        .unwrap_or_default()
+                ),
+            },
+            session.wallpaper
+        );
+    }
+    Ok(())
+}
+
+/// Removes the state of every session, other than the current one, without
+/// a running daemon.
+fn clean_sessions() -> Result<()> {
+    let current = get_session_id();
+    for session in list_sessions()? {
+        if session.id != current && !is_daemon_running(&session.id) {
+            remove_session(&session.id)?;
+            info!("removed stale session {}", session.id);
+        }
+    }
+    Ok(())
 }
 
 pub fn run(settings: Settings, action: Actions) {
+    init_logger(
+        if matches!(action, Actions::Foreground(_)) {
+            LogFormat::Journald
+        } else {
+            LogFormat::Plain
+        },
+        &settings,
+    );
+
     match action {
-        Actions::Launch => run_daemon(&settings),
-        Actions::Toggle => toggle(&settings),
-        Actions::Get => match get_next_wallpaper(&settings) {
+        Actions::Launch(overrides) => {
+            if let Err(err) = run_daemon(settings, overrides) {
+                error!("{err}");
+            }
+        }
+        Actions::Foreground(overrides) => {
+            if let Err(err) = run_foreground(settings, overrides) {
+                error!("{err}");
+            }
+        }
+        Actions::Toggle => send_or(&Command::Next, || {
+            toggle(&settings);
+            Ok(())
+        }),
+        Actions::Get => match peek_next_wallpaper(&settings) {
             Ok(wallpaper) => println!("{wallpaper}"),
-            Err(err) => eprintln!("Error, {err}"),
+            Err(err) => error!("{err}"),
         },
-        Actions::Resume => match set_on() {
+        Actions::Restore => {
+            if let Err(err) = restore(&settings) {
+                error!("{err}");
+            }
+        }
+        Actions::Resume => send_or(&Command::Resume, resume),
+        Actions::Stop => send_or(&Command::Pause(None), || pause(None).map(drop)),
+        Actions::PauseUntil(until) => send_or(&Command::Pause(Some(until)), || {
+            pause(Some(until)).map(drop)
+        }),
+        Actions::Set(wall) => {
+            // Resolved here, the daemon does not run in the same directory.
+            let wall = match pick_wallpaper(&settings, &wall)
+                .and_then(|path| get_first_image(&settings, path))
+            {
+                Ok(wall) => wall.to_string(),
+                Err(err) => {
+                    error!("{err}");
+                    return;
+                }
+            };
+            send_or(&Command::Set(wall.clone()), || {
+                info!("changing wallpaper to {wall}: set with --set");
+                update_wallpaper(&settings, &wall)
+            });
+        }
+        Actions::SetTags(filter) => match set_tag_filter(filter) {
             Ok(()) => (),
-            Err(err) => eprintln!("Error, {err}"),
+            Err(err) => error!("{err}"),
         },
-        Actions::Stop => match set_off() {
+        Actions::ClearTags => match clear_tag_filter() {
             Ok(()) => (),
-            Err(err) => eprintln!("Error, {err}"),
+            Err(err) => error!("{err}"),
         },
-        Actions::Set(wall) => match update_wallpaper(&settings, &wall) {
+        Actions::ListSessions => match print_sessions() {
             Ok(()) => (),
-            Err(err) => eprintln!("Error, {err}"),
+            Err(err) => error!("{err}"),
         },
-    }
-}
-
-fn run_daemon(settings: &Settings) {
-    let stdout = File::create("/tmp/wallshift.out").unwrap();
-    let stderr = File::create("/tmp/wallshift.err").unwrap();
-
-    let daemonize = Daemonize::new()
-        .pid_file("/tmp/wallshift.pid")
-        .chown_pid_file(true)
-        .stdout(stdout) // Redirect stdout
-        .stderr(stderr); // Redirect stderr
-
-    match daemonize.start() {
-        Ok(()) => launch_wallpaper_loop(settings),
-        Err(e) => eprintln!("Error, {e}"),
-    }
-}
-
-fn launch_wallpaper_loop(settings: &Settings) {
-    loop {
-        match get_next_wallpaper(settings) {
-            Ok(mut wallpaper) => {
-                let path = wallpaper.to_string();
-                if let Err(err) = update_wallpaper(settings, &path) {
-                    eprintln!("Error, {err}");
-                    thread::sleep(Duration::from_secs(settings.sleep_time));
-                } else {
-                    let sleep_time = match wallpaper.get_sleep_time(settings) {
-                        Ok(seconds) => seconds,
-                        Err(err) => {
-                            eprintln!("Error, {err}");
-                            settings.sleep_time
-                        }
-                    };
-
-                    thread::sleep(Duration::from_secs(sleep_time));
-                }
+        Actions::CleanSessions => match clean_sessions() {
+            Ok(()) => (),
+            Err(err) => error!("{err}"),
+        },
+        Actions::QueueAdd(wallpaper) => {
+            match pick_wallpaper(&settings, &wallpaper).and_then(|path| {
+                get_first_image(&settings, path.clone())?;
+                push_queue(&path.to_string_lossy())
+            }) {
+                Ok(()) => (),
+                Err(err) => error!("{err}"),
             }
-            Err(err) => eprintln!("Error, {err}"),
         }
+        Actions::QueueList => match print_queue() {
+            Ok(()) => (),
+            Err(err) => error!("{err}"),
+        },
+        Actions::QueueClear => match clear_queue() {
+            Ok(()) => (),
+            Err(err) => error!("{err}"),
+        },
+        Actions::QueueRemove(wallpaper) => match unqueue(&settings, &wallpaper) {
+            Ok(()) => (),
+            Err(err) => error!("{err}"),
+        },
     }
+
+    wait_lockscreen();
 }
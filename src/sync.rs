@@ -0,0 +1,108 @@
+use std::{fs, os::unix::fs::symlink, path::PathBuf};
+
+use anyhow::{anyhow, Result};
+use image::ImageFormat;
+use log::{debug, error};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    atomic,
+    configuration::{expand_home, Settings},
+    path::is_later_frame,
+    pipeline::{export_image, get_cache_dir, Resolution},
+};
+
+/// How a sync target is updated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SyncMode {
+    /// A symbolic link to the wallpaper.
+    #[default]
+    Symlink,
+    /// A copy of the wallpaper.
+    Copy,
+    /// The wallpaper converted to PNG.
+    Png,
+    /// The wallpaper converted to JPEG.
+    Jpeg,
+}
+
+/// A fixed path kept up to date with the current wallpaper, for programs that
+/// cannot follow the changes (display manager themes, terminals...).
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SyncTarget {
+    pub path: String,
+    #[serde(default)]
+    pub mode: SyncMode,
+    /// Size (`<width>x<height>`) of the converted images, the wallpaper is
+    /// scaled and cropped to fill it. The original size is kept if not set.
+    #[serde(default)]
+    pub size: Option<String>,
+}
+
+impl SyncTarget {
+    fn update(&self, wallpaper: &str) -> Result<()> {
+        let path = PathBuf::from(expand_home(&self.path));
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let size = self.size.as_deref().map(Resolution::try_from).transpose()?;
+
+        match self.mode {
+            SyncMode::Symlink => atomic::replace(&path, |tmp| Ok(symlink(wallpaper, tmp)?)),
+            SyncMode::Copy => atomic::replace(&path, |tmp| {
+                fs::copy(wallpaper, tmp)?;
+                Ok(())
+            }),
+            SyncMode::Png => export_image(wallpaper, ImageFormat::Png, size, &path),
+            SyncMode::Jpeg => export_image(wallpaper, ImageFormat::Jpeg, size, &path),
+        }
+    }
+}
+
+/// Returns the sync targets: the configured ones and the symbolic link
+/// `$XDG_CACHE_HOME/wallshift/current`, which is always provided.
+fn get_targets(settings: &Settings) -> Result<Vec<SyncTarget>> {
+    let current = get_cache_dir()?.join("current");
+    let current = SyncTarget {
+        path: current
+            .to_str()
+            .ok_or(anyhow!("failed to convert cache path to str"))?
+            .to_owned(),
+        mode: SyncMode::Symlink,
+        size: None,
+    };
+
+    Ok(std::iter::once(current)
+        .chain(settings.sync_targets.iter().cloned())
+        .collect())
+}
+
+/// Updates every sync target with the new wallpaper. A failing target does
+/// not prevent the others from being updated.
+///
+/// Converting an image takes time, so the `png` and `jpeg` targets are only
+/// updated with the first frame of animated wallpapers.
+pub fn sync_wallpaper(settings: &Settings, wallpaper: &str) {
+    let targets = match get_targets(settings) {
+        Ok(targets) => targets,
+        Err(err) => {
+            error!("{err}");
+            return;
+        }
+    };
+
+    let later_frame = is_later_frame(settings, wallpaper);
+    for target in targets {
+        if later_frame && matches!(target.mode, SyncMode::Png | SyncMode::Jpeg) {
+            debug!(
+                "skipped the sync target {} for the frame {wallpaper}",
+                target.path
+            );
+            continue;
+        }
+        if let Err(err) = target.update(wallpaper) {
+            error!("failed to update the sync target {}: {err}", target.path);
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    env,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram},
+    },
+};
+
+use anyhow::Result;
+
+/// Sends a state notification to the service manager, see `sd_notify(3)`.
+///
+/// Does nothing if wallshift was not started by a service manager
+/// expecting notifications (`$NOTIFY_SOCKET` is not set).
+pub fn notify(state: &str) -> Result<()> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let path = path.to_string_lossy();
+
+    // Names starting with `@` refer to abstract sockets.
+    let address = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path.as_ref())?,
+    };
+
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &address)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process, sync::Mutex, time::Duration};
+
+    use super::*;
+
+    /// `NOTIFY_SOCKET` is shared by the tests of the process.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// Sends the notifications through `notify` with `NOTIFY_SOCKET` set to
+    /// `address`, and returns what `socket` received.
+    fn notify_to(socket: &UnixDatagram, address: &str) -> Vec<String> {
+        let _env = ENV.lock().unwrap_or_else(|err| err.into_inner());
+        env::set_var("NOTIFY_SOCKET", address);
+        let sent = ["READY=1", "STATUS=Wallpaper: /wallpapers/a.png"]
+            .into_iter()
+            .map(notify)
+            .collect::<Result<Vec<_>>>();
+        env::remove_var("NOTIFY_SOCKET");
+        sent.unwrap();
+
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut buffer = [0; 256];
+        (0..2)
+            .map(|_| {
+                let length = socket.recv(&mut buffer).unwrap();
+                String::from_utf8_lossy(&buffer[..length]).into_owned()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn notify_path_socket() {
+        let dir = env::temp_dir().join(format!("wallshift-notify-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify.sock");
+        let _ = fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+
+        let received = notify_to(&socket, path.to_str().unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(received, ["READY=1", "STATUS=Wallpaper: /wallpapers/a.png"]);
+    }
+
+    #[test]
+    fn notify_abstract_socket() {
+        let name = format!("wallshift-notify-{}", process::id());
+        let address = SocketAddr::from_abstract_name(&name).unwrap();
+        let socket = UnixDatagram::bind_addr(&address).unwrap();
+
+        let received = notify_to(&socket, &format!("@{name}"));
+        assert_eq!(received, ["READY=1", "STATUS=Wallpaper: /wallpapers/a.png"]);
+    }
+
+    #[test]
+    fn no_notify_socket() {
+        let _env = ENV.lock().unwrap_or_else(|err| err.into_inner());
+        env::remove_var("NOTIFY_SOCKET");
+        assert!(notify("READY=1").is_ok());
+    }
+}
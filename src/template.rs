@@ -0,0 +1,199 @@
+use std::{fs, path::Path, process::Command, time::Duration};
+
+use anyhow::{anyhow, Result};
+use log::debug;
+
+use crate::{
+    atomic,
+    configuration::{expand_home, Settings, Template},
+    palette::Palette,
+    path::is_later_frame,
+    process,
+};
+
+/// Renders every configured template with the palette of the given wallpaper
+/// and runs their reload commands.
+///
+/// A failing template does not prevent the others from being rendered, all
+/// the errors are reported together. Animated wallpapers are only rendered
+/// with their first frame.
+pub fn render_templates(settings: &Settings, wallpaper: &str) -> Result<()> {
+    if settings.templates.is_empty() {
+        return Ok(());
+    }
+    if is_later_frame(settings, wallpaper) {
+        debug!("skipped the templates for the frame {wallpaper}");
+        return Ok(());
+    }
+
+    let palette = Palette::from_image(Path::new(wallpaper))?;
+
+    let errors = settings
+        .templates
+        .iter()
+        .filter_map(|template| {
+            render_template(settings, template, &palette, wallpaper)
+                .err()
+                .map(|err| format!("{}: {err}", template.template))
+        })
+        .collect::<Vec<_>>();
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!("failed to render templates: {}", errors.join(", ")))
+    }
+}
+
+fn render_template(
+    settings: &Settings,
+    template: &Template,
+    palette: &Palette,
+    wallpaper: &str,
+) -> Result<()> {
+    let input = Path::new(&expand_home(&settings.templates_dir)).join(&template.template);
+    let contents = fs::read_to_string(&input)
+        .map_err(|err| anyhow!("failed to read {}: {err}", input.display()))?;
+
+    let output = expand_home(&template.output);
+    let output = Path::new(&output);
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    atomic::write(output, render(&contents, palette, wallpaper))?;
+
+    if let Some(reload) = &template.reload {
+        process::run(
+            Command::new("sh").arg("-c").arg(reload),
+            Duration::from_secs(settings.command_timeout),
+        )
+        .map_err(|err| anyhow!("reload command `{reload}` failed: {err}"))?;
+    }
+
+    Ok(())
+}
+
+/// Replaces the `{{placeholder}}` occurrences of the template.
+///
+/// Supported placeholders are `color0`..`color15`, `background`, `foreground`
+/// and `cursor`, which are replaced by `#rrggbb`, and `wallpaper`, which is
+/// replaced by the path of the wallpaper. Colors accept a `.strip` suffix
+/// (`{{color1.strip}}`) to omit the leading `#`.
+/// Unknown placeholders are left untouched.
+fn render(template: &str, palette: &Palette, wallpaper: &str) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some(end) = after.find("}}") else {
+            rest = &rest[start..];
+            break;
+        };
+
+        let key = after[..end].trim();
+        match replacement(key, palette, wallpaper) {
+            Some(value) => rendered.push_str(&value),
+            None => rendered.push_str(&rest[start..start + end + 4]),
+        }
+        rest = &after[end + 2..];
+    }
+    rendered.push_str(rest);
+
+    rendered
+}
+
+fn replacement(key: &str, palette: &Palette, wallpaper: &str) -> Option<String> {
+    if key == "wallpaper" {
+        return Some(wallpaper.to_owned());
+    }
+
+    match key.split_once('.') {
+        Some((name, "strip")) => palette.get(name).map(|color| color.strip()),
+        Some(_) => None,
+        None => palette.get(key).map(|color| color.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::palette::Color;
+
+    fn palette() -> Palette {
+        let mut colors = [Color { r: 0, g: 0, b: 0 }; 16];
+        colors[1] = Color {
+            r: 0xab,
+            g: 0x12,
+            b: 0x34,
+        };
+        Palette {
+            colors,
+            background: Color { r: 0, g: 0, b: 0 },
+            foreground: Color {
+                r: 0xff,
+                g: 0xff,
+                b: 0xff,
+            },
+            cursor: Color {
+                r: 0x10,
+                g: 0x20,
+                b: 0x30,
+            },
+        }
+    }
+
+    #[test]
+    fn render_colors() {
+        assert_eq!(
+            render("fg={{foreground}} c1={{color1}}", &palette(), "/w.png"),
+            "fg=#ffffff c1=#ab1234"
+        );
+    }
+
+    #[test]
+    fn render_strip() {
+        assert_eq!(
+            render("{{color1.strip}} {{cursor.strip}}", &palette(), "/w.png"),
+            "ab1234 102030"
+        );
+    }
+
+    #[test]
+    fn render_wallpaper() {
+        assert_eq!(
+            render("image = {{wallpaper}}", &palette(), "/a b/w.png"),
+            "image = /a b/w.png"
+        );
+    }
+
+    #[test]
+    fn render_whitespace_inside_braces() {
+        assert_eq!(
+            render("{{ color1 }} {{  wallpaper\t}}", &palette(), "/w.png"),
+            "#ab1234 /w.png"
+        );
+    }
+
+    #[test]
+    fn keep_unknown_keys() {
+        assert_eq!(
+            render(
+                "{{color16}} {{ name }} {{color1.upper}} {{color1}}",
+                &palette(),
+                "/w.png"
+            ),
+            "{{color16}} {{ name }} {{color1.upper}} #ab1234"
+        );
+    }
+
+    #[test]
+    fn keep_unclosed_braces() {
+        assert_eq!(
+            render("{{color1}} {{color1", &palette(), "/w.png"),
+            "#ab1234 {{color1"
+        );
+        assert_eq!(render("a }} b {{", &palette(), "/w.png"), "a }} b {{");
+    }
+}
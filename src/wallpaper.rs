@@ -1,27 +1,71 @@
 use rand::RngExt;
 use std::{
-    fs::{read_dir, DirEntry},
-    path::PathBuf,
-    process::Command,
+    fs::{self, read_dir, DirEntry},
+    io::BufReader,
+    path::{Path, PathBuf},
+    time::Duration,
 };
 
 use crate::{
     configuration::Settings,
-    data::{get_current_wallpaper, is_on, save_wallpaper},
+    data::{
+        get_current_wallpaper, get_queue, get_tag_filter, get_up_next, remove_from_queue,
+        save_wallpaper, set_up_next,
+    },
+    lockscreen::update_lockscreen,
+    metadata::{get_metadata, is_metadata_file, Library, TagFilter},
     path::{File, ImagePath},
+    pipeline::prepare_wallpaper,
+    setter::{Fit, Setter},
+    sync::sync_wallpaper,
+    template::render_templates,
 };
 
 use anyhow::{anyhow, Result};
+use image::ImageReader;
+use log::{debug, error, warn};
 
-fn get_random_file(files: Vec<&DirEntry>) -> PathBuf {
-    let random_number = rand::rng().random_range(0..files.len());
-    files.get(random_number).unwrap().path()
+/// Picks a random file, taking into account the weight of each one.
+fn get_random_file(library: &Library, files: Vec<&DirEntry>) -> Result<PathBuf> {
+    let weights = files
+        .iter()
+        .map(|entry| u64::from(library.get(&entry.path()).weight()))
+        .collect::<Vec<_>>();
+
+    let total = weights.iter().sum::<u64>();
+    if total == 0 {
+        return Err(anyhow!("no enabled wallpapers in the wallpaper directory"));
+    }
+
+    let mut random_number = rand::rng().random_range(0..total);
+    for (entry, weight) in files.iter().zip(weights) {
+        if random_number < weight {
+            return Ok(entry.path());
+        }
+        random_number -= weight;
+    }
+    unreachable!()
+}
+
+/// Gets the tag filter of the selection: the one stored with `--tags` or
+/// `--exclude-tags`, or the default one of the configuration.
+fn get_selection_filter(settings: &Settings) -> TagFilter {
+    get_tag_filter()
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| TagFilter {
+            include: settings.tags.clone(),
+            exclude: settings.exclude_tags.clone(),
+        })
 }
 
 /// Gets a random wallpaper from the wallpaper directory.
 /// It can also return a folder, which will be handled by the caller.
-/// Hidden files will be ignored.
+/// Hidden files and metadata files will be ignored, as well as the wallpapers
+/// disabled in their metadata or not matching the tag filter.
 pub fn get_random_wallpaper(settings: &Settings) -> Result<File> {
+    let library = Library::load(settings);
+    let tag_filter = get_selection_filter(settings);
     let files = read_dir(settings.wallpaper_dir.clone())?
         .filter_map(|entry| {
             if let Ok(entry) = entry {
@@ -30,6 +74,8 @@ pub fn get_random_wallpaper(settings: &Settings) -> Result<File> {
                     .to_str()
                     .expect("failed to convert file name to str")
                     .starts_with('.')
+                    || is_metadata_file(&entry.path())
+                    || !tag_filter.matches(&library.get_tags(&entry.path()))
                 {
                     None
                 } else {
@@ -42,6 +88,9 @@ pub fn get_random_wallpaper(settings: &Settings) -> Result<File> {
         .collect::<Vec<_>>();
 
     if files.is_empty() {
+        if !tag_filter.is_empty() {
+            return Err(anyhow!("no wallpapers match the tag filter"));
+        }
         return Err(anyhow!("no wallpapers in the wallpaper directory"));
     }
 
@@ -58,9 +107,9 @@ pub fn get_random_wallpaper(settings: &Settings) -> Result<File> {
                 entry_path_str != current_wallpaper_str
             })
             .collect::<Vec<_>>();
-        get_random_file(files)
+        get_random_file(&library, files)?
     } else {
-        get_random_file(files.iter().collect())
+        get_random_file(&library, files.iter().collect())?
     };
 
     File::new(path).ok_or(anyhow!("failed to get random wallpaper"))
@@ -111,8 +160,191 @@ pub fn get_next_animated_wallpaper(settings: &Settings, path: &File) -> Result<O
     }
 }
 
-/// Gets the next wallpaper.
-pub fn get_next_wallpaper(settings: &Settings) -> Result<ImagePath> {
+/// Gets the wallpaper the next change will use, without changing it. It is
+/// picked the first time and stored, so every call returns the same one
+/// until the wallpaper changes.
+///
+/// An animation that has been started goes on until its last frame, then
+/// the queued wallpapers come first.
+pub fn peek_next_wallpaper(settings: &Settings) -> Result<ImagePath> {
+    let up_next = get_up_next()?.and_then(|path| ImagePath::new(PathBuf::from(path)));
+    if let Some(up_next) = up_next {
+        return Ok(up_next);
+    }
+
+    let next = match get_next_frame(settings) {
+        Some(frame) => frame,
+        None => match get_queued_wallpaper(settings)? {
+            Some(queued) => queued,
+            None => get_next_wallpaper(settings)?,
+        },
+    };
+    set_up_next(Some(next.to_string()))?;
+    Ok(next)
+}
+
+/// Gets the next frame of the current wallpaper, if it is an animation that
+/// has not reached its last frame.
+fn get_next_frame(settings: &Settings) -> Option<ImagePath> {
+    let mut current = get_current_wallpaper().ok()?;
+    if !current.is_animated(settings) {
+        return None;
+    }
+    get_next_animated_wallpaper(settings, &current)
+        .ok()
+        .flatten()
+}
+
+/// Gets the first wallpaper of the queue, the first frame for animations.
+/// Queued wallpapers that are not wallpapers anymore are removed from the
+/// queue.
+fn get_queued_wallpaper(settings: &Settings) -> Result<Option<ImagePath>> {
+    for queued in get_queue()? {
+        match get_first_image(settings, PathBuf::from(&queued)) {
+            Ok(wallpaper) => return Ok(Some(wallpaper)),
+            Err(err) => {
+                warn!("removed {queued} from the queue: {err}");
+                remove_from_queue(|_, wallpaper| wallpaper == queued)?;
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Finds the wallpapers matching a name given by the user, trying in order:
+/// - an existing path,
+/// - a path relative to the wallpaper directory,
+/// - the name of a wallpaper without its extension, or of an animation,
+/// - a fuzzy match: the wallpapers whose name contains it, or else contains
+///   its characters in the same order, ignoring the case.
+///
+/// Several wallpapers are returned when the name is ambiguous.
+pub fn find_wallpapers(settings: &Settings, name: &str) -> Result<Vec<PathBuf>> {
+    let path = Path::new(name);
+    if path.exists() {
+        // Not `fs::canonicalize`: symlinks must not be resolved, the path is
+        // compared with the wallpaper directory to find animations.
+        return Ok(vec![std::path::absolute(path)?.components().collect()]);
+    }
+
+    let wallpaper_dir = &settings.wallpaper_dir;
+    let relative = Path::new(wallpaper_dir).join(name);
+    if relative.exists() {
+        return Ok(vec![relative]);
+    }
+
+    let mut entries = read_dir(wallpaper_dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            !is_metadata_file(path)
+                && path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| !name.starts_with('.'))
+        })
+        .collect::<Vec<_>>();
+    entries.sort();
+
+    let stem = |path: &PathBuf| {
+        path.file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or_default()
+            .to_lowercase()
+    };
+    let name = name.to_lowercase();
+    let is_subsequence = |stem: &str| {
+        let mut chars = stem.chars();
+        name.chars().all(|c| chars.any(|other| other == c))
+    };
+
+    let matching = [
+        |stem: &str, name: &str| stem == name,
+        |stem: &str, name: &str| stem.contains(name),
+    ]
+    .iter()
+    .map(|matches| {
+        entries
+            .iter()
+            .filter(|path| matches(&stem(path), &name))
+            .cloned()
+            .collect::<Vec<_>>()
+    })
+    .find(|matching| !matching.is_empty())
+    .unwrap_or_else(|| {
+        entries
+            .iter()
+            .filter(|path| is_subsequence(&stem(path)))
+            .cloned()
+            .collect()
+    });
+
+    Ok(matching)
+}
+
+/// Finds the wallpaper matching a name given by the user, see
+/// `find_wallpapers`. Fails if none or several match.
+pub fn resolve_wallpaper(settings: &Settings, name: &str) -> Result<PathBuf> {
+    let mut wallpapers = find_wallpapers(settings, name)?;
+    match wallpapers.len() {
+        0 => Err(anyhow!("no wallpaper named `{name}`")),
+        1 => Ok(wallpapers.remove(0)),
+        _ => Err(anyhow!(
+            "`{name}` matches several wallpapers:\n{}",
+            wallpapers
+                .iter()
+                .map(|path| format!("  {}", path.display()))
+                .collect::<Vec<_>>()
+                .join("\n")
+        )),
+    }
+}
+
+/// Gets the image to display for a wallpaper: itself, or the first frame for
+/// an animation. Directories are only accepted if they are animations, in the
+/// wallpaper directory, and the files of animations if they are frames.
+pub fn get_first_image(settings: &Settings, path: PathBuf) -> Result<ImagePath> {
+    let wallpaper_dir = Path::new(&settings.wallpaper_dir);
+    if path.is_dir() && path.parent() != Some(wallpaper_dir) {
+        return Err(anyhow!(
+            "{} is a directory, not an animation of the wallpaper directory",
+            path.display()
+        ));
+    }
+    let mut image = match File::new(path.clone()) {
+        Some(File::Image(image)) => image,
+        Some(folder) => get_next_animated_wallpaper(settings, &folder)?
+            .ok_or(anyhow!("the animation {} has no frames", path.display()))?,
+        None => return Err(anyhow!("{} is not a wallpaper", path.display())),
+    };
+    // The folders of the wallpaper directory only hold animations.
+    let in_animation = image.path().parent().and_then(Path::parent) == Some(wallpaper_dir);
+    if in_animation && !image.is_animated(settings) {
+        return Err(anyhow!(
+            "{} is in an animation but is not a numbered frame",
+            image.path().display()
+        ));
+    }
+    check_image(image.path())?;
+    Ok(image)
+}
+
+/// Checks that a file is an image, from its first bytes, so that a file that
+/// no setter can display is not picked.
+fn check_image(path: &Path) -> Result<()> {
+    // Not `ImageReader::open`, which trusts the extension.
+    let format = fs::File::open(path)
+        .and_then(|file| ImageReader::new(BufReader::new(file)).with_guessed_format())
+        .map_err(|err| anyhow!("failed to read {}: {err}", path.display()))?
+        .format();
+    if format.is_none() {
+        return Err(anyhow!("{} is not an image", path.display()));
+    }
+    Ok(())
+}
+
+/// Gets a new wallpaper following the current one: the next frame of
+/// an animation or a random wallpaper.
+fn get_next_wallpaper(settings: &Settings) -> Result<ImagePath> {
     let mut current_wallpaper = get_current_wallpaper().unwrap_or(get_random_wallpaper(settings)?);
     let mut new_wallpaper = get_random_wallpaper(settings)?;
     if current_wallpaper.is_animated(settings) {
@@ -144,26 +376,134 @@ pub fn update_animated(settings: &Settings, path: &File) -> Result<ImagePath> {
     }
 }
 
-/// Updates the current wallpaper using feh.
-/// If the option is selected it will also update the betterlockscreen wallpaper.
-pub fn update_wallpaper(settings: &Settings, path: &str) -> Result<()> {
-    if !is_on()? {
-        return Ok(());
+/// Sets the wallpaper with one setter, placed according to its fit mode.
+/// If `prescale` is enabled, the setter is given a copy scaled to the screen
+/// resolution.
+fn set_with(settings: &Settings, setter: Setter, path: &str, fit: Fit) -> Result<()> {
+    let timeout = Duration::from_secs(settings.command_timeout);
+
+    // The wallpaper is rendered by wallshift if the setter cannot handle the
+    // fit mode, or to pre-scale it. The original is used if that fails.
+    if settings.prescale || setter.fit_args(fit).is_none() {
+        match prepare_wallpaper(settings, path, fit) {
+            Ok(prepared) => return setter.set(&prepared, Fit::Fill, timeout),
+            Err(err) => warn!("failed to render {path}, using the original: {err}"),
+        }
     }
+    setter.set(path, fit, timeout)
+}
 
-    // TODO: allow user to choose other wallpaper setter
-    Command::new("feh").arg("--bg-fill").arg(path).output()?;
+/// Updates the current wallpaper, trying each configured setter in turn
+/// until one succeeds. The state is only updated once the wallpaper is set.
+/// The sync targets are then updated and, if one is configured, an update of
+/// the lock screen is queued.
+/// The configured templates are rendered with the colors of the new wallpaper.
+pub fn update_wallpaper(settings: &Settings, path: &str) -> Result<()> {
+    apply_wallpaper(settings, path, true)
+}
+
+/// Sets the current wallpaper again, e.g. after the X server restarted, like
+/// `update_wallpaper` but leaving the state of the session as it is: the
+/// queue and the next wallpaper are kept.
+pub fn restore_wallpaper(settings: &Settings, path: &str) -> Result<()> {
+    apply_wallpaper(settings, path, false)
+}
+
+fn apply_wallpaper(settings: &Settings, path: &str, save: bool) -> Result<()> {
+    let fit = get_metadata(settings, Path::new(path))
+        .fit
+        .unwrap_or_else(|| settings.get_fit(path));
+    debug!("setting {path} with fit mode {}", fit.name());
+
+    let mut errors = Vec::new();
+    let set = settings
+        .setters
+        .iter()
+        .any(|setter| match set_with(settings, *setter, path, fit) {
+            Ok(()) => true,
+            Err(err) => {
+                warn!("{} failed to set {path}: {err}", setter.name());
+                errors.push(err.to_string());
+                false
+            }
+        });
+    if !set {
+        return Err(anyhow!(
+            "failed to set {path}: {}",
+            if errors.is_empty() {
+                "no setter configured".to_owned()
+            } else {
+                errors.join("; ")
+            }
+        ));
+    }
 
     // Saves the current wallpaper
-    save_wallpaper(path)?;
+    if save {
+        save_wallpaper(path)?;
+    }
 
-    // Updates the betterlockscreen wallpaper
-    if settings.betterlockscreen {
-        Command::new("betterlockscreen")
-            .arg("-u")
-            .arg(path)
-            .output()?;
+    // Updates the copies of the wallpaper at fixed paths
+    sync_wallpaper(settings, path);
+
+    // Updates the lock screen, in the background
+    update_lockscreen(settings, path);
+
+    // Renders the theme templates with the colors of the new wallpaper
+    if let Err(err) = render_templates(settings, path) {
+        error!("{err}");
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    #[test]
+    fn find_wallpapers_by_name() {
+        let dir = env::temp_dir().join(format!("wallshift-find-{}", process::id()));
+        fs::create_dir_all(dir.join("anim")).unwrap();
+        for file in [
+            "beach.png",
+            "beach-sunset.png",
+            "sunset.jpg",
+            "beach.yml",
+            ".beach-night.png",
+        ] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+        let settings = Settings {
+            wallpaper_dir: dir.to_string_lossy().into_owned(),
+            ..Settings::default()
+        };
+        let find = |name: &str| {
+            find_wallpapers(&settings, name)
+                .unwrap()
+                .iter()
+                .map(|path| path.strip_prefix(&dir).unwrap().to_path_buf())
+                .collect::<Vec<_>>()
+        };
+        let paths = |names: &[&str]| names.iter().map(PathBuf::from).collect::<Vec<_>>();
+
+        // An exact name is preferred over the names containing it.
+        assert_eq!(find("beach"), paths(&["beach.png"]));
+        assert_eq!(find("Sunset"), paths(&["sunset.jpg"]));
+        assert_eq!(find("anim"), paths(&["anim"]));
+        // The names containing it are preferred over the subsequences.
+        assert_eq!(find("sun"), paths(&["beach-sunset.png", "sunset.jpg"]));
+        assert_eq!(find("each-"), paths(&["beach-sunset.png"]));
+        assert_eq!(find("bsn"), paths(&["beach-sunset.png"]));
+        assert_eq!(find("night"), paths(&[]));
+        // Paths relative to the wallpaper directory, or existing paths.
+        assert_eq!(find("beach.yml"), paths(&["beach.yml"]));
+        assert_eq!(
+            find(&dir.join("sunset.jpg").to_string_lossy()),
+            paths(&["sunset.jpg"])
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}